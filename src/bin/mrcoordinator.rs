@@ -6,13 +6,28 @@ use map_reduce_rs::mr::coordinator::*;
 use tarpc::{server::incoming::Incoming, tokio_serde::formats::Json};
 use tokio::time::sleep;
 
+/// A losing backup racer can still be physically running (and thus `Busy`) after `done()` flips
+/// true, since its own state only flips to `Idle` once *it* reports in, even though the winner's
+/// report already marked the job finished. Poll `all_workers_settled()` at this interval and wait
+/// for every worker to become `Idle`/`Done`/`Dead` before exiting, so that straggler's final RPC
+/// doesn't have its socket severed out from under it
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Cap how long the shutdown drain waits on a worker that never settles (e.g. one that crashed
+/// without yet being marked `Dead` by the lease checker); past this, exit anyway rather than
+/// hanging forever
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// The lease checker's tranquilizer multiplier: its idle interval grows by this factor on each
+/// consecutive quiet pass (mirrors `DEFAULT_TRANQUILITY_MULTIPLIER` in `mrworker.rs`)
+const LEASE_CHECK_TRANQUILITY_MULTIPLIER: u32 = 2;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = env::args().collect::<Vec<String>>();
     if args.len() != 4 {
-        // Note here the `input file number` is number of files to read for each map task
-        // Which is the `map_n` in `Coordinator`
-        // The input file will start from `pg-0.txt` to `pg-{0 + map_n - 1}.txt`
+        // Note here the `input file number` is the number of input files to content-defined-chunk
+        // for job #0, named `pg-0-0.txt` to `pg-0-{input file number - 1}.txt`; the actual number
+        // of map tasks (`map_n`) is decided by the chunker and may differ from this count
         println!("Usage: cargo run --bin mrcoordinator -- <input files number> <reduce task number> <worker number>");
         return Ok(());
     }
@@ -29,6 +44,16 @@ async fn main() -> anyhow::Result<()> {
     // Create a new Coordinator
     let coordinator = Arc::new(Mutex::new(Coordinator::new(map_n, reduce_n, worker_n)));
 
+    // Try to resume from a previous crash before serving any RPCs, so reconnecting workers see
+    // the recovered state rather than a freshly reset one
+    if coordinator.lock().unwrap().recover() {
+        println!("[Recovery] The Coordinator has successfully resumed from `coordinator.wal`");
+    }
+
+    // Run the lease checker as a self-scheduling background worker instead of polling it from
+    // the main loop, so it backs off on its own once the cluster has settled down
+    coordinator.lock().unwrap().spawn_lease_checker(LEASE_CHECK_TRANQUILITY_MULTIPLIER);
+
     // Create a clone for RPC server
     let coordinator_clone = Arc::clone(&coordinator);
 
@@ -49,23 +74,28 @@ async fn main() -> anyhow::Result<()> {
 
     println!("[Preparation] The Coordinator RPC server has launched and is currently serving, please launch #{} worker process(es) to begin MapReduce", worker_n);
 
-    // Used to check the lease every 5 seconds, to detect the sudden crash from workers
-    let lease_period = 5;
-    let mut lease_time_counter = 0;
     while !coordinator.lock().unwrap().done() {
-        // If not finished, sleep for a while in the main thread
+        // If not finished, sleep for a while in the main thread; the lease checker runs
+        // independently on its own background task
         sleep(Duration::from_secs(1)).await;
-        lease_time_counter += 1;
-        if lease_time_counter == lease_period {
-            // Check the map or reduce lease every `lease_period` seconds
-            // Since the MapReduce will only be in either map or reduce phase without overlapping
-            println!("[Check Lease] Check the current lease to see if any worker is offline");
-            assert!(coordinator.lock().unwrap().check_lease());
-            // Reset the time counter
-            lease_time_counter = 0;
-        }
     }
 
+    // Every queued job has finished, but a losing backup racer may still be mid-task; don't tear
+    // the RPC server down until every worker has actually settled (or the bounded grace period
+    // runs out), so its last in-flight request still gets a reply instead of having its socket
+    // severed out from under it
+    println!("[Shutdown] Every queued job has finished, waiting for every worker to settle (up to {:?})", SHUTDOWN_GRACE_PERIOD);
+    let drain_start = std::time::Instant::now();
+    while !coordinator.lock().unwrap().all_workers_settled() && drain_start.elapsed() < SHUTDOWN_GRACE_PERIOD {
+        sleep(SHUTDOWN_POLL_INTERVAL).await;
+    }
+    if coordinator.lock().unwrap().all_workers_settled() {
+        println!("[Shutdown] Every worker has settled");
+    } else {
+        println!("[Shutdown] Grace period expired with some worker(s) still unsettled, exiting anyway");
+    }
+    println!("[Shutdown] The Coordinator will now exit");
+
     println!(
         "\nThe MapReduce process has finished, please check the results at `mr-*.txt`\n{}\n{}",
         "You could run `make clean` to clean the generated intermediate files",
@@ -0,0 +1,65 @@
+use std::{net::SocketAddr, env};
+
+use map_reduce_rs::mr::{coordinator::ServerClient, worker::IntermediateFormat};
+use tarpc::{tokio_serde::formats::Json, client, context};
+
+/// Parse the CLI's human-readable intermediate format name into the wire enum, mirroring the
+/// variant names of `IntermediateFormat` in kebab case
+fn parse_intermediate_format(name: &str) -> IntermediateFormat {
+    match name {
+        "plain-text" => IntermediateFormat::PlainText,
+        "length-prefixed" => IntermediateFormat::LengthPrefixed,
+        "json-line" => IntermediateFormat::JsonLine,
+        _ => panic!("[Submit] Unknown intermediate format \"{}\", expected one of plain-text | length-prefixed | json-line", name),
+    }
+}
+
+/// A small client-side tool to queue an additional job onto an already-running Coordinator, so
+/// multiple jobs can actually be submitted for its priority scheduler to pick between, without
+/// every caller having to hand-roll the RPC call itself
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = env::args().collect::<Vec<String>>();
+    if args.len() != 6 {
+        println!("Usage: cargo run --bin mrsubmit -- <input files number> <reduce task number> <job priority> <function name> <intermediate format: plain-text | length-prefixed | json-line>");
+        return Ok(());
+    }
+
+    let (input_file_n, reduce_n, job_priority) = (args[1].parse::<i32>()?, args[2].parse::<i32>()?, args[3].parse::<u32>()?);
+    let function_name = args[4].clone();
+    let intermediate_format = parse_intermediate_format(&args[5]);
+
+    let server_address = "127.0.0.1:1030".parse::<SocketAddr>().unwrap();
+    let client_transport = match tarpc::serde_transport::tcp::connect(server_address, Json::default).await {
+        Ok(t) => t,
+        Err(e) => {
+            println!(
+                "[Submit] Failed to connect to the RPC server, please check the Coordinator status!\n{}{}",
+                "Error Message: ",
+                e
+            );
+            return Ok(());
+        }
+    };
+    let client = ServerClient::new(client::Config::default(), client_transport).spawn();
+
+    // Reserve the job id first, so its `pg-{job_id}-{i}.txt` input files can be named correctly
+    // before the Coordinator ever tries to open them
+    let job_id = client.reserve_job_id(context::current()).await?;
+    println!(
+        "[Submit] Reserved job #{}, stage its input files as `pg-{}-0.txt` .. `pg-{}-{}.txt` if not already in place",
+        job_id, job_id, job_id, input_file_n - 1
+    );
+
+    let submitted = client.submit_job(context::current(), job_id, input_file_n, reduce_n, job_priority, function_name.clone(), intermediate_format).await?;
+    if !submitted {
+        println!("[Submit] Failed to submit job #{}: one or more of its input files couldn't be opened", job_id);
+        return Ok(());
+    }
+    println!(
+        "[Submit] Job #{} submitted with priority {} running \"{}\" ({} input files, {} reduce tasks)",
+        job_id, job_priority, function_name, input_file_n, reduce_n
+    );
+
+    Ok(())
+}
@@ -1,9 +1,16 @@
-use std::{net::SocketAddr, env, time::Duration};
+use std::{net::SocketAddr, env, time::{Duration, Instant}};
 
-use map_reduce_rs::mr::{coordinator::ServerClient, worker::Worker};
+use map_reduce_rs::mr::{coordinator::ServerClient, worker::{Tranquilizer, Worker}};
 use tarpc::{tokio_serde::formats::Json, client, context};
 use tokio::time::sleep;
 
+/// The default tranquilizer idle-backoff multiplier, used when no CLI override is given
+const DEFAULT_TRANQUILITY_MULTIPLIER: u32 = 10;
+/// The default tranquilizer sliding window size, used when no CLI override is given
+const DEFAULT_WINDOW_SIZE: usize = 16;
+/// Idle backoff never sleeps longer than this, regardless of the rolling average task duration
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
 /// Basically, the worker processes will only do two things in general
 /// 1. If there is a not yet finished job, no matter `map` or `reduce`, just do it
 /// 2. If the previously assigned job has been finished, ask the coordinator for a new job
@@ -12,17 +19,20 @@ use tokio::time::sleep;
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = env::args().collect::<Vec<String>>();
-    if args.len() != 3 {
-        // Note here the `input file number` is number of files to read for each map task
-        // Which is the `map_n` in `Coordinator`
-        // The input file will start from `pg-0.txt` to `pg-{0 + map_n - 1}.txt`
-        println!("Usage: cargo run --bin mrworker -- <input files number> <reduce task number>");
+    if args.len() != 3 && args.len() != 5 {
+        // Note here the `input file number` is the number of input files job #0 (the job the
+        // Coordinator is pre-seeded with) was chunked from, named `pg-0-0.txt` to
+        // `pg-0-{input file number - 1}.txt`; the actual number of map tasks is decided by
+        // content-defined chunking and may differ from this count
+        println!("Usage: cargo run --bin mrworker -- <input files number> <reduce task number> [tranquility multiplier] [window size]");
         return Ok(());
     }
 
     let (map_n, reduce_n) = (args[1].parse::<i32>()?, args[2].parse::<i32>()?);
+    let tranquility_multiplier = if args.len() == 5 { args[3].parse::<u32>()? } else { DEFAULT_TRANQUILITY_MULTIPLIER };
+    let window_size = if args.len() == 5 { args[4].parse::<usize>()? } else { DEFAULT_WINDOW_SIZE };
 
-    println!("[Worker Configuration] #{} Map Tasks | #{} Reduce Tasks", map_n, reduce_n);
+    println!("[Worker Configuration] #{} Map Tasks | #{} Reduce Tasks | Tranquility Multiplier {} | Window Size {}", map_n, reduce_n, tranquility_multiplier, window_size);
 
     // The server address, you'll want to substitute this with your own configuration
     let server_address = "127.0.0.1:1030".parse::<SocketAddr>().unwrap();
@@ -49,6 +59,10 @@ async fn main() -> anyhow::Result<()> {
     // Let's create a worker
     let mut worker = Worker::new(map_n, reduce_n);
 
+    // Tracks recently completed task durations so idle backoff can self-tune to the job's own
+    // granularity instead of polling at a fixed interval
+    let mut tranquilizer = Tranquilizer::new(window_size, tranquility_multiplier, MAX_BACKOFF);
+
     // Then let's start the worker logic
     loop {
         let cur_state = worker.get_state();
@@ -56,53 +70,94 @@ async fn main() -> anyhow::Result<()> {
             false => {
                 // In map phase
                 assert!(worker.get_map_id() == -1);
-                // Ask the coordinator for a new map task id
-                let map_task_id = client.get_map_task(context::current()).await?;
+                // Ask the coordinator for a new map task, scoped to whichever job currently has
+                // the highest-priority pending work
+                let (job_id, map_task_id) = client.get_map_task(context::current(), worker_id).await?;
                 if map_task_id == -2 {
                     // Still in preparation phase
                     // Just go to sleep
-                    println!("[Preparation] There is no enough worker process to start the MapReduce, go to sleep");
-                    // Sleep for a while
-                    sleep(Duration::from_secs(1)).await;
+                    let backoff = tranquilizer.backoff();
+                    println!("[Preparation] There is no enough worker process to start the MapReduce, go to sleep for {:?}", backoff);
+                    sleep(backoff).await;
                     continue;
                 }
                 if map_task_id == -1 {
-                    // There is no more available map task
+                    // There is no more available map task in any queued job
                     // Let's prepare for the reduce phase
                     println!("[Map] No available map tasks at present, change the state to reduce and go to sleep");
                     worker.change_state();
                     // Let's sleep for a while, waiting the coordinator to change the above state
-                    sleep(Duration::from_secs(1)).await;
+                    let backoff = tranquilizer.backoff();
+                    sleep(backoff).await;
+                    continue;
+                }
+                if map_task_id == -3 {
+                    // Every job with pending map work currently has all its tasks in flight,
+                    // but none are yet eligible for a stale/backup re-dispatch; back off and
+                    // poll again rather than falling through with a bogus `job_id == -1`
+                    let backoff = tranquilizer.backoff();
+                    println!("[Map] No map task available to dispatch right now, go to sleep for {:?}", backoff);
+                    sleep(backoff).await;
                     continue;
                 }
                 // Otherwise, let's do the map job!
+                worker.set_job_id(job_id);
                 worker.set_map_id(map_task_id);
+                // Learn which application and intermediate format this job was registered with
+                let (function_name, intermediate_format) = client.get_job_config(context::current(), job_id).await?;
+                worker.set_job_config(function_name, intermediate_format);
+                // Resolve which content-defined chunk this map task id actually covers
+                let (chunk_file, chunk_offset, chunk_length) = client.get_map_chunk(context::current(), job_id, map_task_id).await?;
+                worker.set_map_chunk(chunk_file, chunk_offset, chunk_length);
                 // Assert the map succeeds
+                let map_start = Instant::now();
                 assert!(worker.map().await?);
+                tranquilizer.record(map_start.elapsed());
                 // Report to the coordinator
-                assert!(client.report_map_task_finish(context::current(), map_task_id).await?);
+                assert!(client.report_map_task_finish(context::current(), job_id, map_task_id, worker_id).await?);
+                let (tasks_per_sec, avg_task_ms) = tranquilizer.throughput();
+                println!("[Tranquilizer] {} task(s) completed so far, {:.2} tasks/sec, {:.1}ms avg task duration", tranquilizer.completed_n(), tasks_per_sec, avg_task_ms);
             }
             true => {
                 // In reduce phase
                 assert!(worker.get_map_id() == -1 && worker.get_reduce_id() == -1);
-                // Ask the coordinator for a new reduce task id
-                let reduce_task_id = client.get_reduce_task(context::current()).await?;
+                // Ask the coordinator for a new reduce task, scoped to whichever job currently
+                // has the highest-priority pending work
+                let (job_id, reduce_task_id) = client.get_reduce_task(context::current(), worker_id).await?;
                 if reduce_task_id == -2 {
-                    // The reduce phase has not yet started, go back to sleep
-                    println!("[Reduce] The reduce phase has not yet started due to unfinished map tasks, go to sleep");
-                    sleep(Duration::from_secs(1)).await;
+                    // No job's reduce phase has started yet due to unfinished map tasks, go back to sleep
+                    let backoff = tranquilizer.backoff();
+                    println!("[Reduce] The reduce phase has not yet started due to unfinished map tasks, go to sleep for {:?}", backoff);
+                    sleep(backoff).await;
                     continue;
                 }
                 if reduce_task_id == -1 {
-                    // Meaning the MapReduce is at an end, this worker can thus safely exit
+                    // Meaning every queued job's MapReduce is at an end, this worker can thus safely exit
                     println!("[Reduce] No available reduce tasks at present, this worker process will thus terminate\nWish you a good day :)");
                     return Ok(());
                 }
+                if reduce_task_id == -3 {
+                    // Every job with pending reduce work currently has all its tasks in flight,
+                    // but none are yet eligible for a stale/backup re-dispatch; back off and
+                    // poll again rather than falling through with a bogus `job_id == -1`
+                    let backoff = tranquilizer.backoff();
+                    println!("[Reduce] No reduce task available to dispatch right now, go to sleep for {:?}", backoff);
+                    sleep(backoff).await;
+                    continue;
+                }
                 // Otherwise, let's do the reduce job!
+                worker.set_job_id(job_id);
                 worker.set_reduce_id(reduce_task_id);
+                // Learn which application and intermediate format this job was registered with
+                let (function_name, intermediate_format) = client.get_job_config(context::current(), job_id).await?;
+                worker.set_job_config(function_name, intermediate_format);
+                let reduce_start = Instant::now();
                 assert!(worker.reduce().await?);
+                tranquilizer.record(reduce_start.elapsed());
                 // Report to the coordinator
-                assert!(client.report_reduce_task_finish(context::current(), reduce_task_id).await?);
+                assert!(client.report_reduce_task_finish(context::current(), job_id, reduce_task_id, worker_id).await?);
+                let (tasks_per_sec, avg_task_ms) = tranquilizer.throughput();
+                println!("[Tranquilizer] {} task(s) completed so far, {:.2} tasks/sec, {:.1}ms avg task duration", tranquilizer.completed_n(), tasks_per_sec, avg_task_ms);
             }
         }
     }
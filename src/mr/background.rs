@@ -0,0 +1,40 @@
+//! A generic, self-scheduling background worker abstraction, ported from Garage: a `Worker`
+//! reports whether each pass of `work()` found something to do, and `spawn` uses that to decide
+//! how soon to run it again — promptly while busy, backed off (per the worker's own pacing) once
+//! idle, so a quiet worker doesn't needlessly contend on whatever locks it touches
+
+use std::time::Duration;
+
+/// The outcome of a single background worker pass
+pub enum WorkerState {
+    /// This pass found something to do; `spawn` runs it again promptly
+    Busy,
+    /// This pass found nothing to do; `spawn` waits `idle_interval` before running it again
+    Idle,
+}
+
+/// A self-scheduling background task, driven by `spawn` on its own Tokio task until the process
+/// exits
+pub trait Worker: Send + 'static {
+    /// Run a single pass of this worker's work
+    async fn work(&mut self) -> WorkerState;
+
+    /// How long `spawn` should wait before calling `work` again after an `Idle` pass. Defaults
+    /// to a fixed 1 second; workers with their own adaptive pacing (e.g. a tranquility factor)
+    /// can grow this over consecutive idle passes instead
+    fn idle_interval(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+}
+
+/// Drive `worker` forever on its own Tokio task
+pub fn spawn<W: Worker>(mut worker: W) {
+    tokio::spawn(async move {
+        loop {
+            match worker.work().await {
+                WorkerState::Busy => {}
+                WorkerState::Idle => tokio::time::sleep(worker.idle_interval()).await,
+            }
+        }
+    });
+}
@@ -4,32 +4,347 @@ use futures::future::{Ready, ready};
 use tarpc::context;
 use tokio::time::Instant;
 
+use crate::mr::background;
+use crate::mr::splitter::{self, Chunk};
+use crate::mr::worker::IntermediateFormat;
+
+/// A task is considered a straggler, and thus eligible for a backup (speculative) run,
+/// once it has been outstanding for longer than this multiple of its phase's median
+/// completion time so far (mirrors the Cerberus scheduler's backup-task heuristic)
+const BACKUP_TASK_THRESHOLD_MULTIPLIER: u32 = 3;
+
+/// Once at most this many tasks remain in flight in a phase with no more fresh tasks left to
+/// hand out, a second idle worker may race an already-assigned task immediately rather than
+/// waiting for it to cross the median-duration straggler threshold above (mirrors Google
+/// MapReduce's heuristic of backing up whatever's left once a phase is almost done)
+const BACKUP_NEAR_COMPLETION_REMAINING: usize = 3;
+
+/// The lease checker's idle interval resets to this, in milliseconds, the moment a pass finds
+/// (and resets) a stale task or worker, so a cascade of staleness is caught promptly rather than
+/// waiting out whatever backoff the previous quiet period had grown to
+const LEASE_CHECK_BASE_INTERVAL_MS: u64 = 1_000;
+/// The lease checker's idle interval never grows past this, no matter how many consecutive quiet
+/// passes it sees
+const LEASE_CHECK_MAX_INTERVAL_MS: u64 = 30_000;
+
+/// Compute the median of a set of task completion durations, used as the baseline against
+/// which a still-running task is judged to be a straggler
+fn median_duration(durations: &[Duration]) -> Option<Duration> {
+    if durations.is_empty() {
+        return None;
+    }
+    let mut sorted = durations.to_vec();
+    sorted.sort();
+    Some(sorted[sorted.len() / 2])
+}
+
+
+/// A worker's last known status, modeled on Garage's background worker states. Tracked so an
+/// operator can query `list_workers` to see which workers are active, idle, or dead, and so
+/// `check_lease` has a basis for declaring a worker `Dead` that's independent of any single task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum WorkerState {
+    /// Currently holds and is executing a map or reduce task
+    Busy,
+    /// Holds no task and is actively polling the coordinator for one
+    Idle,
+    /// Holds no task and is backing off because its job still has other tasks in flight
+    Throttled,
+    /// Has been told there's no more work of any kind left for it and may exit
+    Done,
+    /// Hasn't contacted the coordinator within the staleness window; presumed crashed
+    Dead,
+}
+
+/// A worker's current status, keyed by worker id in `Coordinator::workers`. Unlike `WorkerState`,
+/// this doesn't derive `Serialize`/`Deserialize` since `Instant` can't cross the wire; `list_workers`
+/// returns a flattened `(worker_id, state, task_id, seconds_since_last_contact)` tuple instead,
+/// the same way `get_map_chunk` unpacks a `Chunk` rather than returning it directly
 #[derive(Debug, Clone)]
-pub struct Coordinator {
-    /// Here `true` indicates the map task has finished, `false` indicates the map task is running
-    map_tasks: Arc<Mutex<HashMap<i32, bool>>>,
-    /// The global unique map task id, which starts from 0
-    map_id: Arc<Mutex<i32>>,
-    /// Same as `map_tasks`
-    reduce_tasks: Arc<Mutex<HashMap<i32, bool>>>,
-    /// The global unique reduce task id, which starts from 0
-    reduce_id: Arc<Mutex<i32>>,
-    /// The number of input files, which is also the number of map tasks
+struct WorkerInfo {
+    /// The worker's current state
+    state: WorkerState,
+    /// The task id it currently holds, or `-1` if it holds none
+    task_id: i32,
+    /// The last time this worker made any RPC contact with the coordinator
+    last_heartbeat: Instant,
+}
+
+/// The outcome of trying to hand a worker a task from a single job
+enum TaskDispatch {
+    /// A task id was handed out, either fresh, stale, or a backup run
+    Assigned(i32),
+    /// This job has no task to hand out right now, but isn't finished either
+    Wait,
+    /// This job has nothing left to dispatch for this phase
+    Done,
+}
+
+/// A single MapReduce job, tracked independently so several jobs can share one worker pool.
+/// Mirrors what used to be flat fields on `Coordinator` before multi-job support was added
+#[derive(Debug, Clone)]
+struct Job {
+    /// The global unique job id, assigned in submission order starting from 0
+    job_id: i32,
+    /// Higher priority jobs are scheduled ahead of lower priority ones (mirrors Cerberus's
+    /// `Task.job_priority`)
+    job_priority: u32,
+    /// The number of map tasks, i.e. the number of content-defined chunks produced by the
+    /// splitter across this job's input files (decoupled from the input file count)
     map_n: i32,
     /// The number of reduce tasks
     reduce_n: i32,
-    /// The number of worker processes
-    worker_n: i32,
+    /// The number of input files this job's map tasks were chunked from; kept around so
+    /// recovery can re-derive `map_chunks` deterministically instead of persisting them
+    input_file_n: i32,
+    /// The name of the registered application (see `mr::function::resolve`) this job runs
+    function_name: String,
+    /// The on-disk format of this job's intermediate files
+    intermediate_format: IntermediateFormat,
+    /// The byte-range chunk each map task id is responsible for
+    map_chunks: HashMap<i32, Chunk>,
+    /// The global unique map task id within this job, which starts from 0
+    map_id: i32,
+    /// The global unique reduce task id within this job, which starts from 0
+    reduce_id: i32,
+    /// Here `true` indicates the map task is currently assigned, `false` indicates it's stale
+    map_tasks: HashMap<i32, bool>,
+    /// Same as `map_tasks`
+    reduce_tasks: HashMap<i32, bool>,
     /// Indicates if the map phase has finished
-    map_finish: Arc<Mutex<bool>>,
+    map_finish: bool,
     /// Indicates if the reduce phase has finished
-    reduce_finish: Arc<Mutex<bool>>,
+    reduce_finish: bool,
+    /// The map lease, used to track the map tasks granted to workers (`check_lease` marks a task stale once its lease is 5 seconds old)
+    map_leases: HashMap<i32, Instant>,
+    /// The reduce lease, used to track the reduce tasks granted to workers (the time period is the same with above)
+    reduce_leases: HashMap<i32, Instant>,
+    /// The time each map task was first dispatched to a worker, kept across lease renewals/backup
+    /// re-dispatches so straggler detection is based on total outstanding time, not just the lease
+    map_dispatch_time: HashMap<i32, Instant>,
+    /// Same as `map_dispatch_time`, but for reduce tasks
+    reduce_dispatch_time: HashMap<i32, Instant>,
+    /// The set of map task ids that have already been reported finished by some worker
+    /// (the "has_completed_before" flag), so a backup racer's late report is a harmless no-op
+    map_done: HashSet<i32>,
+    /// Same as `map_done`, but for reduce tasks
+    reduce_done: HashSet<i32>,
+    /// The set of worker ids ever assigned to each map task id, so the near-completion backup
+    /// path doesn't race a worker against itself; rebuilt empty on recovery since it's pure
+    /// in-memory scheduling bookkeeping rather than durable state
+    map_assignments: HashMap<i32, HashSet<i32>>,
+    /// Same as `map_assignments`, but for reduce tasks
+    reduce_assignments: HashMap<i32, HashSet<i32>>,
+    /// The completion durations of finished map tasks in this job, used to compute the median
+    /// task duration that drives straggler detection
+    map_durations: Vec<Duration>,
+    /// Same as `map_durations`, but for reduce tasks
+    reduce_durations: Vec<Duration>,
+}
+
+impl Job {
+    /// Build a new job by content-defined-chunking each of its `input_file_n` input files
+    /// (named `pg-{job_id}-{i}.txt` by convention, `i` from 0 to `input_file_n - 1`); the
+    /// resulting number of chunks becomes `map_n`, decoupling map fan-out from file count.
+    /// Returns `None` if any of those input files can't be opened, so a caller building this
+    /// job out of a live RPC call can report the failure instead of propagating a panic
+    fn new(job_id: i32, input_file_n: i32, reduce_n: i32, job_priority: u32, function_name: String, intermediate_format: IntermediateFormat) -> Option<Self> {
+        let mut map_chunks = HashMap::new();
+        for i in 0..input_file_n {
+            let file_name = format!("pg-{}-{}.txt", job_id, i);
+            for chunk in splitter::split_file(&file_name)? {
+                let task_id = map_chunks.len() as i32;
+                map_chunks.insert(task_id, chunk);
+            }
+        }
+        let map_n = map_chunks.len() as i32;
+        Some(Self {
+            job_id,
+            job_priority,
+            map_n,
+            reduce_n,
+            input_file_n,
+            function_name,
+            intermediate_format,
+            map_chunks,
+            map_id: 0,
+            reduce_id: 0,
+            map_tasks: HashMap::new(),
+            reduce_tasks: HashMap::new(),
+            map_finish: false,
+            reduce_finish: false,
+            map_leases: HashMap::new(),
+            reduce_leases: HashMap::new(),
+            map_dispatch_time: HashMap::new(),
+            reduce_dispatch_time: HashMap::new(),
+            map_done: HashSet::new(),
+            reduce_done: HashSet::new(),
+            map_assignments: HashMap::new(),
+            reduce_assignments: HashMap::new(),
+            map_durations: Vec::new(),
+            reduce_durations: Vec::new(),
+        })
+    }
+
+    /// Check if this job as a whole has finished
+    fn done(&self) -> bool {
+        self.map_finish && self.reduce_finish
+    }
+
+    /// Try to hand out a map task to `worker_id` for this job: the next fresh task while any
+    /// remain; once every fresh task has been handed out, a stale task first, then an
+    /// immediate backup if the phase is nearly done, then a backup on a straggler, otherwise
+    /// report whether the job is merely waiting on in-flight tasks or has nothing left to ever
+    /// dispatch
+    fn dispatch_map_task(&mut self, worker_id: i32) -> TaskDispatch {
+        if self.map_id == self.map_n {
+            for (&k, &v) in &self.map_tasks.clone() {
+                if v {
+                    continue;
+                }
+                println!("[Map] Job #{} staled map task #{} detected, the previous worker may have gone offline, assigned this task to a new worker", self.job_id, k);
+                self.map_tasks.insert(k, true);
+                assert!(!self.map_leases.contains_key(&k));
+                self.map_leases.insert(k, Instant::now());
+                self.map_dispatch_time.insert(k, Instant::now());
+                self.map_assignments.entry(k).or_default().insert(worker_id);
+                return TaskDispatch::Assigned(k);
+            }
+
+            // Once only a handful of tasks remain in flight, race a second worker on one of
+            // them right away rather than waiting for it to cross the median-duration
+            // straggler threshold, so a single slow tail task can't stall the whole phase
+            if self.map_leases.len() <= BACKUP_NEAR_COMPLETION_REMAINING {
+                for &k in self.map_dispatch_time.clone().keys() {
+                    if self.map_done.contains(&k) {
+                        continue;
+                    }
+                    if self.map_assignments.get(&k).map_or(false, |workers| workers.contains(&worker_id)) {
+                        // This worker is already racing itself on this task, look for another
+                        continue;
+                    }
+                    println!("[Map] Job #{} is nearing completion ({} task(s) left in flight), dispatching an immediate backup for map task #{}", self.job_id, self.map_leases.len(), k);
+                    self.map_assignments.entry(k).or_default().insert(worker_id);
+                    return TaskDispatch::Assigned(k);
+                }
+            }
+
+            // No outright stale task, but a task that's been running far longer than the job's
+            // median is likely a straggler worker rather than a crashed one; race a backup
+            // worker on the same task id instead of making everyone wait for the lease to expire
+            if let Some(median) = median_duration(&self.map_durations) {
+                let threshold = median * BACKUP_TASK_THRESHOLD_MULTIPLIER;
+                for (&k, time) in self.map_dispatch_time.clone().iter() {
+                    if self.map_done.contains(&k) {
+                        continue;
+                    }
+                    if time.elapsed() >= threshold {
+                        println!("[Map] Job #{} map task #{} has been outstanding for {:?} (median is {:?}), dispatching a backup worker", self.job_id, k, time.elapsed(), median);
+                        self.map_assignments.entry(k).or_default().insert(worker_id);
+                        return TaskDispatch::Assigned(k);
+                    }
+                }
+            }
+            if !self.map_leases.is_empty() {
+                return TaskDispatch::Wait;
+            }
+            return TaskDispatch::Done;
+        }
+
+        // Otherwise, this should be the normal process
+        self.map_tasks.insert(self.map_id, true);
+        self.map_leases.insert(self.map_id, Instant::now());
+        self.map_dispatch_time.insert(self.map_id, Instant::now());
+        self.map_assignments.entry(self.map_id).or_default().insert(worker_id);
+        let cur_map = self.map_id;
+        self.map_id += 1;
+        println!("[Map] Assigned job #{} map task #{} to worker", self.job_id, cur_map);
+        if self.map_id == self.map_n {
+            println!("[Map] All available map tasks of job #{} have been assigned to worker, wait til all worker processes finish the map phase", self.job_id);
+        }
+        TaskDispatch::Assigned(cur_map)
+    }
+
+    /// Same as `dispatch_map_task`, but for reduce tasks
+    fn dispatch_reduce_task(&mut self, worker_id: i32) -> TaskDispatch {
+        if self.reduce_id == self.reduce_n {
+            for (&k, &v) in &self.reduce_tasks.clone() {
+                if v {
+                    continue;
+                }
+                println!("[Reduce] Job #{} staled reduce task #{} detected, the previous worker may have gone offline, assigned this task to a new worker", self.job_id, k);
+                self.reduce_tasks.insert(k, true);
+                assert!(!self.reduce_leases.contains_key(&k));
+                self.reduce_leases.insert(k, Instant::now());
+                self.reduce_dispatch_time.insert(k, Instant::now());
+                self.reduce_assignments.entry(k).or_default().insert(worker_id);
+                return TaskDispatch::Assigned(k);
+            }
+
+            if self.reduce_leases.len() <= BACKUP_NEAR_COMPLETION_REMAINING {
+                for &k in self.reduce_dispatch_time.clone().keys() {
+                    if self.reduce_done.contains(&k) {
+                        continue;
+                    }
+                    if self.reduce_assignments.get(&k).map_or(false, |workers| workers.contains(&worker_id)) {
+                        continue;
+                    }
+                    println!("[Reduce] Job #{} is nearing completion ({} task(s) left in flight), dispatching an immediate backup for reduce task #{}", self.job_id, self.reduce_leases.len(), k);
+                    self.reduce_assignments.entry(k).or_default().insert(worker_id);
+                    return TaskDispatch::Assigned(k);
+                }
+            }
+
+            if let Some(median) = median_duration(&self.reduce_durations) {
+                let threshold = median * BACKUP_TASK_THRESHOLD_MULTIPLIER;
+                for (&k, time) in self.reduce_dispatch_time.clone().iter() {
+                    if self.reduce_done.contains(&k) {
+                        continue;
+                    }
+                    if time.elapsed() >= threshold {
+                        println!("[Reduce] Job #{} reduce task #{} has been outstanding for {:?} (median is {:?}), dispatching a backup worker", self.job_id, k, time.elapsed(), median);
+                        self.reduce_assignments.entry(k).or_default().insert(worker_id);
+                        return TaskDispatch::Assigned(k);
+                    }
+                }
+            }
+            if !self.reduce_leases.is_empty() {
+                return TaskDispatch::Wait;
+            }
+            return TaskDispatch::Done;
+        }
+
+        self.reduce_tasks.insert(self.reduce_id, true);
+        self.reduce_leases.insert(self.reduce_id, Instant::now());
+        self.reduce_dispatch_time.insert(self.reduce_id, Instant::now());
+        self.reduce_assignments.entry(self.reduce_id).or_default().insert(worker_id);
+        let cur_reduce = self.reduce_id;
+        self.reduce_id += 1;
+        println!("[Reduce] Assigned job #{} reduce task #{} to worker", self.job_id, cur_reduce);
+        if self.reduce_id == self.reduce_n {
+            println!("[Reduce] All available reduce tasks of job #{} have been assigned to worker, wait til all worker processes finish the reduce phase", self.job_id);
+        }
+        TaskDispatch::Assigned(cur_reduce)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Coordinator {
+    /// The queue of jobs currently known to the coordinator, in submission order; scheduling
+    /// picks the highest-priority job with pending work, breaking ties by submission order
+    jobs: Arc<Mutex<Vec<Job>>>,
+    /// The global unique job id, handed out to each submitted job starting from 0
+    next_job_id: Arc<Mutex<i32>>,
+    /// The number of worker processes
+    worker_n: i32,
     /// The global unique worker id, will assign to each worker through RPC, starts from 0 to {worker_n - 1}
     worker_id: Arc<Mutex<i32>>,
-    /// The map lease, used to track the map tasks granted to workers (Will be checked every 5 seconds by default)
-    map_leases: Arc<Mutex<HashMap<i32, Instant>>>,
-    /// The reduce lease, used to track the reduce tasks granted to workers (The time period is the same with above)
-    reduce_leases: Arc<Mutex<HashMap<i32, Instant>>>,
+    /// Each connected worker's last known status, keyed by worker id; see `WorkerInfo`
+    workers: Arc<Mutex<HashMap<i32, WorkerInfo>>>,
+    /// The lease checker's current idle interval, in milliseconds, shared with its background
+    /// `LeaseChecker` so the effective interval can be persisted in the WAL and survive a restart
+    /// rather than resetting to `LEASE_CHECK_BASE_INTERVAL_MS` every time
+    check_interval_ms: Arc<Mutex<u64>>,
     /// The name of logging directory, containing all the logs
     log_dir_name: String,
     /// The file name of the underlying write-ahead-log for coordinator inside log directory
@@ -37,21 +352,19 @@ pub struct Coordinator {
 }
 
 impl Coordinator {
-    /// Create a new coordinator
-    pub fn new(map_n: i32, reduce_n: i32, worker_n: i32) -> Self {
+    /// Create a new coordinator, pre-seeded with a single job (priority 0) so existing
+    /// single-job callers keep working unchanged; further jobs can be queued via `submit_job`
+    pub fn new(input_file_n: i32, reduce_n: i32, worker_n: i32) -> Self {
         Self {
-            map_tasks: Arc::new(Mutex::new(HashMap::new())),
-            map_id: Arc::new(Mutex::new(0)),
-            reduce_tasks: Arc::new(Mutex::new(HashMap::new())),
-            reduce_id: Arc::new(Mutex::new(0)),
-            map_n,
-            reduce_n,
+            jobs: Arc::new(Mutex::new(vec![
+                Job::new(0, input_file_n, reduce_n, 0, String::from("wc"), IntermediateFormat::PlainText)
+                    .expect("[Coordinator] Failed to open job #0's `pg-0-*.txt` input files")
+            ])),
+            next_job_id: Arc::new(Mutex::new(1)),
             worker_n,
-            map_finish: Arc::new(Mutex::new(false)),
-            reduce_finish: Arc::new(Mutex::new(false)),
             worker_id: Arc::new(Mutex::new(0)),
-            map_leases: Arc::new(Mutex::new(HashMap::new())),
-            reduce_leases: Arc::new(Mutex::new(HashMap::new())),
+            workers: Arc::new(Mutex::new(HashMap::new())),
+            check_interval_ms: Arc::new(Mutex::new(LEASE_CHECK_BASE_INTERVAL_MS)),
             log_dir_name: String::from("log"),
             wal_name: String::from("coordinator.wal"),
         }
@@ -63,135 +376,210 @@ impl Coordinator {
         *self.worker_id.lock().unwrap() == self.worker_n
     }
 
-    /// Check if the overall MapReduce process has finished
+    /// Check if every queued job has finished
     pub fn done(&self) -> bool {
-        *self.map_finish.lock().unwrap() && *self.reduce_finish.lock().unwrap()
+        self.jobs.lock().unwrap().iter().all(|job| job.done())
+    }
+
+    /// Every connected worker's last known status, as `(worker_id, state, task_id,
+    /// seconds_since_last_contact)`; shared by the `list_workers` RPC and by the coordinator
+    /// binary's own shutdown drain, which doesn't go through the RPC layer at all since it
+    /// already holds the `Coordinator` directly
+    fn workers_snapshot(&self) -> Vec<(i32, WorkerState, i32, f64)> {
+        self.workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, info)| (id, info.state, info.task_id, info.last_heartbeat.elapsed().as_secs_f64()))
+            .collect()
+    }
+
+    /// Whether every connected worker has wound down: holds no task (`Idle`), has been told
+    /// there's nothing left for it (`Done`), or has gone stale (`Dead`). `None` of these still
+    /// has an in-flight RPC that could be severed by the process exiting, so this is the
+    /// condition the shutdown drain waits for
+    pub fn all_workers_settled(&self) -> bool {
+        self.workers_snapshot()
+            .iter()
+            .all(|(_, state, _, _)| matches!(state, WorkerState::Idle | WorkerState::Done | WorkerState::Dead))
+    }
+
+    /// Return the jobs ordered by descending priority, ties broken by submission order (i.e. by
+    /// their position in `jobs`, since jobs are always pushed in submission order)
+    fn priority_order(jobs: &[Job]) -> Vec<usize> {
+        let mut order = (0..jobs.len()).collect::<Vec<usize>>();
+        order.sort_by(|&a, &b| jobs[b].job_priority.cmp(&jobs[a].job_priority).then(a.cmp(&b)));
+        order
     }
 
-    /// Check the current lease based on the state, reset the task status if the task has staled
+    /// Record that `worker_id` just made RPC contact with the coordinator, updating its state,
+    /// held task id, and heartbeat timestamp (inserting a fresh entry the first time it's seen)
+    fn touch_worker(&self, worker_id: i32, state: WorkerState, task_id: i32) {
+        let mut workers = self.workers.lock().unwrap();
+        workers.insert(worker_id, WorkerInfo { state, task_id, last_heartbeat: Instant::now() });
+    }
+
+    /// Check the current lease based on the state, reset the task status if the task has staled.
+    /// Returns whether this pass actually found (and reset) any staleness, so it can double as
+    /// the `Busy`/`Idle` signal for the background worker that drives it (see `LeaseChecker`)
     pub fn check_lease(&mut self) -> bool {
-        // We should all the locks at first, since the intermediate state may change between the period
-        let _map_id = self.map_id.lock().unwrap();
-        let _reduce_id = self.reduce_id.lock().unwrap();
         let _worker_id = self.worker_id.lock().unwrap();
-        // The resources that will be used later
-        let mut map_tasks = self.map_tasks.lock().unwrap();
-        let mut reduce_tasks = self.reduce_tasks.lock().unwrap();
-        let mut map_leases = self.map_leases.lock().unwrap();
-        let mut reduce_leases = self.reduce_leases.lock().unwrap();
-        let reduce_finish = self.reduce_finish.lock().unwrap();
-        let map_finish = self.map_finish.lock().unwrap();
-
-        if *map_finish && *reduce_finish {
-            // The MapReduce has finished, nothing to check
-            return true;
-        }
-
-        if *map_finish {
-            // The MapReduce should be in the reduce phase
-            println!("[Check Lease] The MapReduce is in reduce phase, begin to check reduce tasks leases");
-            // Sanity check
-            assert!(!*reduce_finish);
-            // Check every reduce task lease, get the outdated ones
-            let stale_reduce_tasks = reduce_leases
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut found_stale = false;
+
+        for job in jobs.iter_mut() {
+            if job.done() {
+                // This job has finished, nothing to check
+                continue;
+            }
+
+            if job.map_finish {
+                // This job should be in the reduce phase
+                println!("[Check Lease] Job #{} is in reduce phase, begin to check reduce tasks leases", job.job_id);
+                assert!(!job.reduce_finish);
+                let stale_reduce_tasks = job.reduce_leases
+                    .iter()
+                    // If the lease has not been updated for 5 seconds, mark it as stale
+                    .filter(|(_, time)| time.elapsed() >= Duration::new(5, 0))
+                    .map(|x| *x.0)
+                    .collect::<HashSet<i32>>();
+                for stale_id in &stale_reduce_tasks {
+                    assert!(job.reduce_tasks.get(stale_id).unwrap());
+                    println!("[Check Lease] Job #{} staled reduce task #{} detected, mark it as staled", job.job_id, stale_id);
+                    job.reduce_tasks.insert(*stale_id, false);
+                    job.reduce_leases.remove_entry(stale_id);
+                    found_stale = true;
+                }
+                continue;
+            }
+
+            // Then this job must be in the map phase
+            assert!(!job.map_finish && !job.reduce_finish);
+            println!("[Check Lease] Job #{} is in map phase, begin to check map tasks leases", job.job_id);
+            let stale_map_tasks = job.map_leases
                 .iter()
                 // If the lease has not been updated for 5 seconds, mark it as stale
                 .filter(|(_, time)| time.elapsed() >= Duration::new(5, 0))
                 .map(|x| *x.0)
                 .collect::<HashSet<i32>>();
-            // Update the corresponding reduce task map and refresh the reduce lease
-            for stale_id in &stale_reduce_tasks {
-                assert!(reduce_tasks.get(stale_id).unwrap());
-                println!("[Check Lease] Staled reduce task #{} detected, mark it as staled", stale_id);
-                reduce_tasks.insert(*stale_id, false);
-                reduce_leases.remove_entry(stale_id);
+            for stale_id in &stale_map_tasks {
+                assert!(job.map_tasks.get(stale_id).unwrap());
+                println!("[Check Lease] Job #{} staled map task #{} detected, mark it as staled", job.job_id, stale_id);
+                job.map_tasks.insert(*stale_id, false);
+                job.map_leases.remove_entry(stale_id);
+                found_stale = true;
             }
-            return true;
         }
+        drop(jobs);
 
-        // Then the MapReduce must in the map phase
-        assert!(!*map_finish && !*reduce_finish);
-        println!("[Check Lease] The MapReduce is in map phase, begin to check map tasks leases");
-        // Check every map task lease, get the outdated ones
-        let stale_map_tasks = map_leases
-            .iter()
-            // If the lease has not been updated for 5 seconds, mark it as stale
-            .filter(|(_, time)| time.elapsed() >= Duration::new(5, 0))
-            .map(|x| *x.0)
-            .collect::<HashSet<i32>>();
-        // Update the corresponding reduce task map and refresh the reduce lease
-        for stale_id in &stale_map_tasks {
-            assert!(map_tasks.get(stale_id).unwrap());
-            println!("[Check Lease] Staled map task #{} detected, mark it as staled", stale_id);
-            map_tasks.insert(*stale_id, false);
-            map_leases.remove_entry(stale_id);
+        // A task going stale is reported per-task above; separately, a worker that hasn't made
+        // any RPC contact at all within the same staleness window is presumed to have crashed
+        // outright, regardless of whether it currently holds a task
+        let mut workers = self.workers.lock().unwrap();
+        for (id, info) in workers.iter_mut() {
+            if info.state == WorkerState::Done || info.state == WorkerState::Dead {
+                continue;
+            }
+            if info.last_heartbeat.elapsed() >= Duration::new(5, 0) {
+                println!("[Check Lease] Worker #{} hasn't made contact in over 5 seconds, marking it as dead", id);
+                info.state = WorkerState::Dead;
+                found_stale = true;
+            }
         }
 
-        true
+        found_stale
+    }
+
+    /// Spawn `check_lease` as a self-scheduling background worker (see `mr::background`): it
+    /// runs back-to-back while it keeps finding stale tasks or workers, and backs off to an
+    /// idle interval that grows by `tranquility`x on each consecutive quiet pass (capped at
+    /// `LEASE_CHECK_MAX_INTERVAL_MS`), so it doesn't needlessly contend on the coordinator's
+    /// mutexes once the cluster has settled down
+    pub fn spawn_lease_checker(&self, tranquility: u32) {
+        background::spawn(LeaseChecker {
+            coordinator: self.clone(),
+            interval_ms: self.check_interval_ms.clone(),
+            tranquility,
+        });
     }
 
     /// This function will serialize the current status of the coordinator to the underlying Write-Ahead-Log
     fn serialize(&self) -> bool {
         // Hold the lock of all the resources that need to be serialized at first
-        let map_id = self.map_id.lock().unwrap();
-        let reduce_id = self.reduce_id.lock().unwrap();
-        let map_tasks = self.map_tasks.lock().unwrap();
-        let reduce_tasks = self.reduce_tasks.lock().unwrap();
-        let map_leases = self.map_leases.lock().unwrap();
-        let reduce_leases = self.reduce_leases.lock().unwrap();
-        let reduce_finish = self.reduce_finish.lock().unwrap();
-        let map_finish = self.map_finish.lock().unwrap();
+        let jobs = self.jobs.lock().unwrap();
+        let next_job_id = self.next_job_id.lock().unwrap();
+        let check_interval_ms = *self.check_interval_ms.lock().unwrap();
 
         println!("[Serialize] Serializing the status of the current coordinator to `coordinator.wal`");
 
-        if let Ok(mut wal_log) = std::fs::File::create(self.log_dir_name.clone() + "/" + &self.wal_name) {
+        // Write to a temp path first and rename into place once the whole snapshot has been
+        // flushed, so a crash mid-write can never leave a half-written log that `recover` trusts
+        let wal_path = self.log_dir_name.clone() + "/" + &self.wal_name;
+        let temp_path = wal_path.clone() + ".tmp";
+
+        if let Ok(mut wal_log) = std::fs::File::create(&temp_path) {
             wal_log.write_all(format!("BEGIN\n").as_bytes()).unwrap();
 
-            // The length of map tasks
-            wal_log.write_all(format!("{}\n", map_tasks.len()).as_bytes()).unwrap();
-            // The individual tasks
-            for (&k, &v) in &*map_tasks {
-                wal_log.write_all(format!("{} {}\n", k, v).as_bytes()).unwrap();
-            }
+            wal_log.write_all(format!("{}\n", *next_job_id).as_bytes()).unwrap();
 
-            // The global unique map id
-            wal_log.write_all(format!("{}\n", *map_id).as_bytes()).unwrap();
+            // The lease checker's effective idle interval, so a restart resumes the same degree
+            // of tranquility-driven backoff rather than starting back at the base interval
+            wal_log.write_all(format!("{}\n", check_interval_ms).as_bytes()).unwrap();
 
-            // The length of reduce tasks
-            wal_log.write_all(format!("{}\n", reduce_tasks.len()).as_bytes()).unwrap();
-            // The individual tasks
-            for (&k, &v) in &*reduce_tasks {
-                wal_log.write_all(format!("{} {}\n", k, v).as_bytes()).unwrap();
-            }
+            // The number of jobs currently queued
+            wal_log.write_all(format!("{}\n", jobs.len()).as_bytes()).unwrap();
+            for job in &*jobs {
+                wal_log.write_all(format!("JOB\n").as_bytes()).unwrap();
+                wal_log.write_all(format!("{} {} {} {} {}\n", job.job_id, job.job_priority, job.input_file_n, job.map_n, job.reduce_n).as_bytes()).unwrap();
+                wal_log.write_all(format!("{} {}\n", job.function_name, job.intermediate_format as u8).as_bytes()).unwrap();
+                wal_log.write_all(format!("{} {} {} {}\n", job.map_id, job.reduce_id, job.map_finish, job.reduce_finish).as_bytes()).unwrap();
 
-            // The global unique reduce id
-            wal_log.write_all(format!("{}\n", *reduce_id).as_bytes()).unwrap();
+                // The length of map tasks
+                wal_log.write_all(format!("{}\n", job.map_tasks.len()).as_bytes()).unwrap();
+                for (&k, &v) in &job.map_tasks {
+                    wal_log.write_all(format!("{} {}\n", k, v).as_bytes()).unwrap();
+                }
 
-            // `map_finish`
-            wal_log.write_all(format!("{}\n", *map_finish).as_bytes()).unwrap();
+                // The length of reduce tasks
+                wal_log.write_all(format!("{}\n", job.reduce_tasks.len()).as_bytes()).unwrap();
+                for (&k, &v) in &job.reduce_tasks {
+                    wal_log.write_all(format!("{} {}\n", k, v).as_bytes()).unwrap();
+                }
 
-            // `reduce_finish`
-            wal_log.write_all(format!("{}\n", *reduce_finish).as_bytes()).unwrap();
+                // The length of map leases
+                wal_log.write_all(format!("{}\n", job.map_leases.len()).as_bytes()).unwrap();
+                // FIXME: Now only recording the map id
+                for (&k, _) in &job.map_leases {
+                    wal_log.write_all(format!("{}\n", k).as_bytes()).unwrap();
+                }
 
-            // The length of map leases
-            wal_log.write_all(format!("{}\n", map_leases.len()).as_bytes()).unwrap();
-            // The individual leases
-            // FIXME: Now only recording the map id
-            for (&k, _) in &*map_leases {
-                wal_log.write_all(format!("{}\n", k).as_bytes()).unwrap();
-            }
+                // The length of reduce leases
+                wal_log.write_all(format!("{}\n", job.reduce_leases.len()).as_bytes()).unwrap();
+                // FIXME: Now only recording the reduce id
+                for (&k, _) in &job.reduce_leases {
+                    wal_log.write_all(format!("{}\n", k).as_bytes()).unwrap();
+                }
 
-            // The length of reduce leases
-            wal_log.write_all(format!("{}\n", reduce_leases.len()).as_bytes()).unwrap();
-            // The individual leases
-            // FIXME: Now only recording the map id
-            for (&k, _) in &*reduce_leases {
-                wal_log.write_all(format!("{}\n", k).as_bytes()).unwrap();
+                // The set of map task ids already reported finished, so a backup racer's late
+                // duplicate report is still recognized as such after recovery
+                wal_log.write_all(format!("{}\n", job.map_done.len()).as_bytes()).unwrap();
+                for &k in &job.map_done {
+                    wal_log.write_all(format!("{}\n", k).as_bytes()).unwrap();
+                }
+
+                // Same as above, but for reduce tasks
+                wal_log.write_all(format!("{}\n", job.reduce_done.len()).as_bytes()).unwrap();
+                for &k in &job.reduce_done {
+                    wal_log.write_all(format!("{}\n", k).as_bytes()).unwrap();
+                }
             }
 
             wal_log.write_all(format!("END\n").as_bytes()).unwrap();
+            wal_log.flush().unwrap();
+            drop(wal_log);
+            std::fs::rename(&temp_path, &wal_path).unwrap();
         } else {
-            println!("[Serialize] Failed to open `coordinator.wal`");
+            println!("[Serialize] Failed to open `coordinator.wal.tmp`");
             return false;
         }
         // Successfully serialized to the underlying Write-Ahead-Log
@@ -199,8 +587,20 @@ impl Coordinator {
     }
 
     /// This function will deserialize the status of coordinator when recovering
-    fn deserialize(&mut self, _wal_vec: Vec<&str>) -> bool {
-        true
+    fn deserialize(&mut self, wal_vec: Vec<&str>) -> bool {
+        match parse_wal(&wal_vec) {
+            Some((next_job_id, check_interval_ms, jobs)) => {
+                println!("[Recovery] Successfully parsed `coordinator.wal`, recovered {} job(s)", jobs.len());
+                *self.next_job_id.lock().unwrap() = next_job_id;
+                *self.check_interval_ms.lock().unwrap() = check_interval_ms;
+                *self.jobs.lock().unwrap() = jobs;
+                true
+            }
+            None => {
+                println!("[Recovery] `coordinator.wal` is truncated or corrupt, falling back to normal mode");
+                false
+            }
+        }
     }
 
     pub fn recover(&mut self) -> bool {
@@ -214,7 +614,9 @@ impl Coordinator {
                 .filter(|x| !x.is_empty())
                 .collect::<Vec<&str>>();
             // Begin the actual recover process by process the latest MapReduce status
-            assert!(self.deserialize(wal_vec));
+            if !self.deserialize(wal_vec) {
+                return false;
+            }
         } else {
             println!("[Recovery] Found no `coordinator.wal`, starts the coordinator in normal mode");
             return false;
@@ -224,164 +626,356 @@ impl Coordinator {
     }
 }
 
+/// Drives `Coordinator::check_lease` as a self-scheduling `background::Worker`: reports `Busy`
+/// (and resets the idle interval back to the base) whenever a pass finds staleness to reset,
+/// otherwise reports `Idle` and grows the interval by `tranquility`x, capped at
+/// `LEASE_CHECK_MAX_INTERVAL_MS`, so a quiet cluster doesn't needlessly contend on the
+/// coordinator's mutexes
+struct LeaseChecker {
+    coordinator: Coordinator,
+    /// Shared with `Coordinator` so the effective interval can be persisted in the WAL
+    interval_ms: Arc<Mutex<u64>>,
+    /// The idle interval grows by this multiple on each consecutive quiet pass
+    tranquility: u32,
+}
+
+impl background::Worker for LeaseChecker {
+    async fn work(&mut self) -> background::WorkerState {
+        let found_stale = self.coordinator.check_lease();
+        let mut interval_ms = self.interval_ms.lock().unwrap();
+        if found_stale {
+            *interval_ms = LEASE_CHECK_BASE_INTERVAL_MS;
+            background::WorkerState::Busy
+        } else {
+            *interval_ms = std::cmp::min(*interval_ms * self.tranquility as u64, LEASE_CHECK_MAX_INTERVAL_MS);
+            background::WorkerState::Idle
+        }
+    }
+
+    fn idle_interval(&self) -> Duration {
+        Duration::from_millis(*self.interval_ms.lock().unwrap())
+    }
+}
+
+/// Pull the next line off the WAL cursor, advancing `idx`; returns `None`
+/// once the cursor runs past the end of the log, signaling truncation to the caller
+fn take_token<'a>(wal_vec: &[&'a str], idx: &mut usize) -> Option<&'a str> {
+    let token = *wal_vec.get(*idx)?;
+    *idx += 1;
+    Some(token)
+}
+
+/// Parse the `BEGIN ... END` framed format written by `Coordinator::serialize`, returning the
+/// recovered `(next_job_id, check_interval_ms, jobs)` triple, or `None` if the log is truncated
+/// or malformed in any way (a corrupt WAL must never be trusted)
+fn parse_wal(wal_vec: &[&str]) -> Option<(i32, u64, Vec<Job>)> {
+    let mut idx = 0usize;
+
+    if take_token(wal_vec, &mut idx)? != "BEGIN" {
+        return None;
+    }
+
+    let next_job_id = take_token(wal_vec, &mut idx)?.parse::<i32>().ok()?;
+    let check_interval_ms = take_token(wal_vec, &mut idx)?.parse::<u64>().ok()?;
+    let job_n = take_token(wal_vec, &mut idx)?.parse::<usize>().ok()?;
+
+    let mut jobs = Vec::new();
+    for _ in 0..job_n {
+        if take_token(wal_vec, &mut idx)? != "JOB" {
+            return None;
+        }
+
+        let mut header = take_token(wal_vec, &mut idx)?.split(' ');
+        let job_id = header.next()?.parse::<i32>().ok()?;
+        let job_priority = header.next()?.parse::<u32>().ok()?;
+        let input_file_n = header.next()?.parse::<i32>().ok()?;
+        let map_n = header.next()?.parse::<i32>().ok()?;
+        let reduce_n = header.next()?.parse::<i32>().ok()?;
+
+        let mut config = take_token(wal_vec, &mut idx)?.split(' ');
+        let function_name = config.next()?.to_string();
+        let intermediate_format = match config.next()?.parse::<u8>().ok()? {
+            0 => IntermediateFormat::PlainText,
+            1 => IntermediateFormat::LengthPrefixed,
+            2 => IntermediateFormat::JsonLine,
+            _ => return None,
+        };
+
+        let mut progress = take_token(wal_vec, &mut idx)?.split(' ');
+        let map_id = progress.next()?.parse::<i32>().ok()?;
+        let reduce_id = progress.next()?.parse::<i32>().ok()?;
+        let map_finish = progress.next()?.parse::<bool>().ok()?;
+        let reduce_finish = progress.next()?.parse::<bool>().ok()?;
+
+        // The content-defined chunks are deterministic given the same input files, so they
+        // aren't worth persisting; just re-derive them the same way `Job::new` would. If an
+        // input file has gone missing since the last serialize, treat the whole WAL as
+        // unrecoverable rather than recovering a job with a silently incomplete map phase
+        let mut map_chunks = HashMap::new();
+        for i in 0..input_file_n {
+            let file_name = format!("pg-{}-{}.txt", job_id, i);
+            for chunk in splitter::split_file(&file_name)? {
+                let task_id = map_chunks.len() as i32;
+                map_chunks.insert(task_id, chunk);
+            }
+        }
+
+        let map_tasks_n = take_token(wal_vec, &mut idx)?.parse::<usize>().ok()?;
+        let mut map_tasks = HashMap::new();
+        for _ in 0..map_tasks_n {
+            let mut entry = take_token(wal_vec, &mut idx)?.split(' ');
+            let k = entry.next()?.parse::<i32>().ok()?;
+            let v = entry.next()?.parse::<bool>().ok()?;
+            map_tasks.insert(k, v);
+        }
+
+        let reduce_tasks_n = take_token(wal_vec, &mut idx)?.parse::<usize>().ok()?;
+        let mut reduce_tasks = HashMap::new();
+        for _ in 0..reduce_tasks_n {
+            let mut entry = take_token(wal_vec, &mut idx)?.split(' ');
+            let k = entry.next()?.parse::<i32>().ok()?;
+            let v = entry.next()?.parse::<bool>().ok()?;
+            reduce_tasks.insert(k, v);
+        }
+
+        // Only the task id was ever persisted for a lease; hand out a fresh `Instant::now()` on
+        // recovery so stale-task detection restarts cleanly instead of flagging every recovered
+        // task as stale on the very first `check_lease` pass
+        let map_leases_n = take_token(wal_vec, &mut idx)?.parse::<usize>().ok()?;
+        let mut map_leases = HashMap::new();
+        let mut map_dispatch_time = HashMap::new();
+        for _ in 0..map_leases_n {
+            let k = take_token(wal_vec, &mut idx)?.parse::<i32>().ok()?;
+            map_leases.insert(k, Instant::now());
+            map_dispatch_time.insert(k, Instant::now());
+        }
+
+        let reduce_leases_n = take_token(wal_vec, &mut idx)?.parse::<usize>().ok()?;
+        let mut reduce_leases = HashMap::new();
+        let mut reduce_dispatch_time = HashMap::new();
+        for _ in 0..reduce_leases_n {
+            let k = take_token(wal_vec, &mut idx)?.parse::<i32>().ok()?;
+            reduce_leases.insert(k, Instant::now());
+            reduce_dispatch_time.insert(k, Instant::now());
+        }
+
+        let map_done_n = take_token(wal_vec, &mut idx)?.parse::<usize>().ok()?;
+        let mut map_done = HashSet::new();
+        for _ in 0..map_done_n {
+            map_done.insert(take_token(wal_vec, &mut idx)?.parse::<i32>().ok()?);
+        }
+
+        let reduce_done_n = take_token(wal_vec, &mut idx)?.parse::<usize>().ok()?;
+        let mut reduce_done = HashSet::new();
+        for _ in 0..reduce_done_n {
+            reduce_done.insert(take_token(wal_vec, &mut idx)?.parse::<i32>().ok()?);
+        }
+
+        jobs.push(Job {
+            job_id,
+            job_priority,
+            map_n,
+            reduce_n,
+            input_file_n,
+            function_name,
+            intermediate_format,
+            map_chunks,
+            map_id,
+            reduce_id,
+            map_tasks,
+            reduce_tasks,
+            map_finish,
+            reduce_finish,
+            map_leases,
+            reduce_leases,
+            map_dispatch_time,
+            reduce_dispatch_time,
+            map_done,
+            reduce_done,
+            // Per-task worker assignments are pure in-memory scheduling bookkeeping, not worth
+            // persisting; an empty map just means no worker is yet excluded from a backup race
+            map_assignments: HashMap::new(),
+            reduce_assignments: HashMap::new(),
+            // The straggler-detection medians are self-healing: an empty window simply means no
+            // backup is dispatched until enough recovered tasks complete to repopulate it
+            map_durations: Vec::new(),
+            reduce_durations: Vec::new(),
+        });
+    }
+
+    if take_token(wal_vec, &mut idx)? != "END" {
+        return None;
+    }
+
+    Some((next_job_id, check_interval_ms, jobs))
+}
+
 /// RPC related for Coordinator
 #[tarpc::service]
 pub trait Server {
-    /// Get the corresponding map task
-    async fn get_map_task() -> i32;
-    /// Get the corresponding reduce task
-    /// Note that reduce phase won't begin until all map tasks have finished
-    async fn get_reduce_task() -> i32;
+    /// Get the corresponding map task, returned as `(job_id, task_id)`. `worker_id` is threaded
+    /// through so the near-completion backup path can avoid racing a worker against itself
+    async fn get_map_task(worker_id: i32) -> (i32, i32);
+    /// Get the corresponding reduce task, returned as `(job_id, task_id)`
+    /// Note that a job's reduce phase won't begin until all of its map tasks have finished
+    async fn get_reduce_task(worker_id: i32) -> (i32, i32);
     /// Get the corresponding worker id
     async fn get_worker_id() -> i32;
+    /// Get the `(file, offset, length)` content-defined chunk a map task id is responsible for
+    async fn get_map_chunk(job_id: i32, task_id: i32) -> (String, u64, u64);
+    /// Reserve the next job id without yet building the job, so a caller (e.g. `mrsubmit`) can
+    /// stage its `pg-{job_id}-{i}.txt` input files under the right name *before* calling
+    /// `submit_job`, rather than having to guess the id in advance
+    async fn reserve_job_id() -> i32;
+    /// Register a job previously reserved via `reserve_job_id` (input file count, reduce task
+    /// count, priority, application name, intermediate file format) at runtime. Returns `false`,
+    /// leaving the reserved id unused, if any of its input files can't be opened, instead of
+    /// panicking and taking down the RPC server's task for every other job
+    async fn submit_job(job_id: i32, input_file_n: i32, reduce_n: i32, job_priority: u32, function_name: String, intermediate_format: IntermediateFormat) -> bool;
+    /// Get the `(function_name, intermediate_format)` a job was registered with, so a worker
+    /// knows which application and intermediate file format to use for its currently-held task
+    async fn get_job_config(job_id: i32) -> (String, IntermediateFormat);
     /// Report map task has finished
-    async fn report_map_task_finish(id: i32) -> bool;
+    async fn report_map_task_finish(job_id: i32, id: i32, worker_id: i32) -> bool;
     /// Report reduce task has finished
-    async fn report_reduce_task_finish(id: i32) -> bool;
+    async fn report_reduce_task_finish(job_id: i32, id: i32, worker_id: i32) -> bool;
     /// Renew the current map task lease
-    async fn renew_map_lease(id: i32) -> bool;
+    async fn renew_map_lease(job_id: i32, id: i32, worker_id: i32) -> bool;
     /// Renew the current reduce task lease
-    async fn renew_reduce_lease(id: i32) -> bool;
+    async fn renew_reduce_lease(job_id: i32, id: i32, worker_id: i32) -> bool;
+    /// List every worker the coordinator has ever heard from, as `(worker_id, state, task_id,
+    /// seconds_since_last_contact)`, so an operator can see which workers are active, idle, or dead
+    async fn list_workers() -> Vec<(i32, WorkerState, i32, f64)>;
 }
 
-/// Register the four RPC functions on Coordinator, which is also the RPC server
+/// Register the RPC functions on Coordinator, which is also the RPC server
 #[tarpc::server]
 impl Server for Coordinator {
-    type GetMapTaskFut = Ready<i32>;
-    type GetReduceTaskFut = Ready<i32>;
+    type GetMapTaskFut = Ready<(i32, i32)>;
+    type GetReduceTaskFut = Ready<(i32, i32)>;
     type GetWorkerIdFut = Ready<i32>;
+    type GetMapChunkFut = Ready<(String, u64, u64)>;
+    type ReserveJobIdFut = Ready<i32>;
+    type SubmitJobFut = Ready<bool>;
+    type GetJobConfigFut = Ready<(String, IntermediateFormat)>;
     type ReportMapTaskFinishFut = Ready<bool>;
     type ReportReduceTaskFinishFut = Ready<bool>;
     type RenewMapLeaseFut = Ready<bool>;
     type RenewReduceLeaseFut = Ready<bool>;
+    type ListWorkersFut = Ready<Vec<(i32, WorkerState, i32, f64)>>;
 
     /// The worker will call this every 1 second to renew the current map task lease
-    fn renew_map_lease(self, _: context::Context, id: i32) -> Self::RenewMapLeaseFut {
-        let mut map_lease = self.map_leases.lock().unwrap();
+    fn renew_map_lease(self, _: context::Context, job_id: i32, id: i32, worker_id: i32) -> Self::RenewMapLeaseFut {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.iter_mut().find(|j| j.job_id == job_id).unwrap();
         // Sanity check
-        assert!(map_lease.contains_key(&id));
+        assert!(job.map_leases.contains_key(&id));
         // Renew the map lease
-        map_lease.insert(id, Instant::now());
+        job.map_leases.insert(id, Instant::now());
+        drop(jobs);
+        self.touch_worker(worker_id, WorkerState::Busy, id);
         ready(true)
     }
 
     /// The worker will call this every 1 second to renew the current reduce task lease
-    fn renew_reduce_lease(self, _: context::Context, id: i32) -> Self::RenewReduceLeaseFut {
-        let mut reduce_lease = self.reduce_leases.lock().unwrap();
+    fn renew_reduce_lease(self, _: context::Context, job_id: i32, id: i32, worker_id: i32) -> Self::RenewReduceLeaseFut {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.iter_mut().find(|j| j.job_id == job_id).unwrap();
         // Sanity check
-        assert!(reduce_lease.contains_key(&id));
+        assert!(job.reduce_leases.contains_key(&id));
         // Renew the reduce lease
-        reduce_lease.insert(id, Instant::now());
+        job.reduce_leases.insert(id, Instant::now());
+        drop(jobs);
+        self.touch_worker(worker_id, WorkerState::Busy, id);
         ready(true)
     }
 
-    /// The worker will call this during map phase through RPC, to get a map task id, represents a input text file
-    fn get_map_task(self, _: context::Context) -> Self::GetMapTaskFut {
-        // First lock the resources
-        let mut cur_map_id = self.map_id.lock().unwrap();
-        let mut cur_map_tasks = self.map_tasks.lock().unwrap();
-        let mut cur_map_leases = self.map_leases.lock().unwrap();
-
+    /// The worker will call this to get a map task, scanning jobs from highest to lowest
+    /// priority and falling back to a lower-priority job only once the higher one is drained
+    fn get_map_task(self, _: context::Context, worker_id: i32) -> Self::GetMapTaskFut {
         if !self.prepare() {
             // This indicates the worker that the preparation phase hasn't ended
-            return ready(-2);
+            self.touch_worker(worker_id, WorkerState::Idle, -1);
+            return ready((-1, -2));
         }
 
-        if *cur_map_id == self.map_n || *self.map_finish.lock().unwrap() {
-            // Check if every task is properly holding by a single worker
-            // FIXME: This may lead to infinite map phase, if the worker crash after being assigned the last map task
-            // Since the other worker may already turn into reduce phase
-            // One way to fix is to notify the reduce phase worker to change state back to map to finish the stale task
-            // But this solution is not so elegant and we must hard-coded some magic number to return to the worker
-            // When the `get_reduce_task` is called, so...
-            for (&k, &v) in &cur_map_tasks.clone() {
-                if v {
-                    continue;
-                }
-                println!("[Map] Staled map task #{} detected, the previous worker may have gone offline, assigned this task to a new worker", k);
-                // Otherwise, there is staled task, assign this task to the worker
-                // Also update the status
-                cur_map_tasks.insert(k, true);
-                // Sanity check
-                assert!(!cur_map_leases.contains_key(&k));
-                // Update the lease
-                cur_map_leases.insert(k, Instant::now());
-                return ready(k);
+        let mut jobs = self.jobs.lock().unwrap();
+        let order = Self::priority_order(&jobs);
+        let mut saw_wait = false;
+        for i in order {
+            if jobs[i].map_finish {
+                continue;
             }
-            if !cur_map_leases.is_empty() {
-                // Should wait to check if there are more stale tasks
-                return ready(-3);
+            match jobs[i].dispatch_map_task(worker_id) {
+                TaskDispatch::Assigned(task_id) => {
+                    let job_id = jobs[i].job_id;
+                    drop(jobs);
+                    self.touch_worker(worker_id, WorkerState::Busy, task_id);
+                    return ready((job_id, task_id));
+                }
+                TaskDispatch::Wait => saw_wait = true,
+                TaskDispatch::Done => {}
             }
-            // No more map tasks are available
-            return ready(-1);
         }
+        drop(jobs);
 
-        // Otherwise, this should be the normal process
-        cur_map_tasks.insert(*cur_map_id, true);
-        // Insert the new lease
-        cur_map_leases.insert(*cur_map_id, Instant::now());
-        let cur_map = *cur_map_id;
-        let ret = ready(cur_map);
-        // Increase the global unique map task id by one
-        *cur_map_id += 1;
-        println!("[Map] Assigned map task #{} to worker", cur_map);
-        if cur_map + 1 == self.map_n {
-            println!("[Map] All available map tasks have been assigned to worker, wait til all worker processes finish the map phase");
-        }
-        // Return the map task id
-        ret
+        if saw_wait {
+            // Some job still has in-flight tasks; should wait to check if there are more stale ones
+            self.touch_worker(worker_id, WorkerState::Throttled, -1);
+            ready((-1, -3))
+        } else {
+            // No job has any map work left to ever dispatch; this worker is about to move on to
+            // the reduce phase rather than exit outright
+            self.touch_worker(worker_id, WorkerState::Idle, -1);
+            ready((-1, -1))
+        }
     }
 
-    /// The worker will call this during reduce phase through RPC, to get a reduce task id, represents a output file
-    fn get_reduce_task(self, _: context::Context) -> Self::GetReduceTaskFut {
-        // First lock the resources
-        let mut cur_reduce_id = self.reduce_id.lock().unwrap();
-        let mut cur_reduce_tasks = self.reduce_tasks.lock().unwrap();
-        let mut cur_reduce_leases = self.reduce_leases.lock().unwrap();
+    /// The worker will call this to get a reduce task, scanning jobs whose map phase has
+    /// finished from highest to lowest priority
+    fn get_reduce_task(self, _: context::Context, worker_id: i32) -> Self::GetReduceTaskFut {
+        let mut jobs = self.jobs.lock().unwrap();
+        let ready_order = Self::priority_order(&jobs)
+            .into_iter()
+            .filter(|&i| jobs[i].map_finish && !jobs[i].reduce_finish)
+            .collect::<Vec<usize>>();
 
-        if !*self.map_finish.lock().unwrap() {
-            // The map phase has not yet finished
-            return ready(-2);
+        if ready_order.is_empty() {
+            if jobs.iter().all(|job| job.done()) {
+                // Nothing left in the queue at all; this worker may safely exit
+                drop(jobs);
+                self.touch_worker(worker_id, WorkerState::Done, -1);
+                return ready((-1, -1));
+            }
+            // At least one job's map phase has not yet finished
+            drop(jobs);
+            self.touch_worker(worker_id, WorkerState::Idle, -1);
+            return ready((-1, -2));
         }
 
-        if *cur_reduce_id == self.reduce_n || *self.reduce_finish.lock().unwrap() {
-            // FIXME: Same as `get_map_tasks`...
-            for (&k, &v) in &cur_reduce_tasks.clone() {
-                if v {
-                    continue;
+        let mut saw_wait = false;
+        for i in ready_order {
+            match jobs[i].dispatch_reduce_task(worker_id) {
+                TaskDispatch::Assigned(task_id) => {
+                    let job_id = jobs[i].job_id;
+                    drop(jobs);
+                    self.touch_worker(worker_id, WorkerState::Busy, task_id);
+                    return ready((job_id, task_id));
                 }
-                println!("[Reduce] Staled reduce task #{} detected, the previous worker may have gone offline, assigned this task to a new worker", k);
-                // Otherwise, there is staled task, assign this task to the worker
-                // Also update the status
-                cur_reduce_tasks.insert(k, true);
-                // Sanity check
-                assert!(!cur_reduce_leases.contains_key(&k));
-                // Update the lease
-                cur_reduce_leases.insert(k, Instant::now());
-                return ready(k);
-            }
-            if !cur_reduce_leases.is_empty() {
-                // Same as `get_map_tasks`
-                return ready(-3);
+                TaskDispatch::Wait => saw_wait = true,
+                TaskDispatch::Done => {}
             }
-            // No more reduce tasks are available
-            return ready(-1);
         }
+        drop(jobs);
 
-        // Otherwise, this should be the normal process
-        cur_reduce_tasks.insert(*cur_reduce_id, true);
-        // Insert the new lease
-        cur_reduce_leases.insert(*cur_reduce_id, Instant::now());
-        let cur_reduce = *cur_reduce_id;
-        let ret = ready(cur_reduce);
-        // Increase the global unique reduce task id by one
-        *cur_reduce_id += 1;
-        println!("[Reduce] Assigned reduce task #{} to worker", cur_reduce);
-        if cur_reduce + 1 == self.reduce_n {
-            println!("[Reduce] All available reduce tasks have been assigned to worker, wait til all worker processes finish the reduce phase");
-        }
-        // Return the reduce task id
-        ret
+        if saw_wait {
+            self.touch_worker(worker_id, WorkerState::Throttled, -1);
+            ready((-1, -3))
+        } else {
+            // No more reduce tasks of any queued job will ever be dispatched; this worker may exit
+            self.touch_worker(worker_id, WorkerState::Done, -1);
+            ready((-1, -1))
+        }
     }
 
     /// The worker will call this function first when connecting, to get a unique worker process identifier
@@ -393,6 +987,8 @@ impl Server for Coordinator {
         let left_num = self.worker_n - cur_num - 1;
         let ret = ready(cur_num);
         *cur_worker_id += 1;
+        drop(cur_worker_id);
+        self.touch_worker(cur_num, WorkerState::Idle, -1);
         println!("[Preparation] Worker #{} connected, #{} more worker(s) needed!", cur_num, left_num);
         if cur_num + 1 == self.worker_n {
             println!("[Preparation] All worker processes have connected, Map Phase will then begin!");
@@ -400,93 +996,168 @@ impl Server for Coordinator {
         ret
     }
 
+    /// List every worker the coordinator has ever heard from, so an operator can see which are
+    /// active, idle, or presumed dead, and what each is working on
+    fn list_workers(self, _: context::Context) -> Self::ListWorkersFut {
+        ready(self.workers_snapshot())
+    }
+
+    /// The worker will call this once it holds a map task id, to learn which byte range of
+    /// which input file it should actually read
+    fn get_map_chunk(self, _: context::Context, job_id: i32, task_id: i32) -> Self::GetMapChunkFut {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.iter().find(|j| j.job_id == job_id).unwrap();
+        let chunk = job.map_chunks.get(&task_id).unwrap();
+        ready((chunk.file.clone(), chunk.offset, chunk.length))
+    }
+
+    /// Reserve the next job id so a caller can stage `pg-{job_id}-{i}.txt` under the right name
+    /// before actually submitting
+    fn reserve_job_id(self, _: context::Context) -> Self::ReserveJobIdFut {
+        let mut next_job_id = self.next_job_id.lock().unwrap();
+        let job_id = *next_job_id;
+        *next_job_id += 1;
+        println!("[Submit Job] Reserved job id #{} for an upcoming submission", job_id);
+        ready(job_id)
+    }
+
+    /// Register a previously reserved job at runtime so already-connected workers pick it up
+    /// without a restart. Reports failure rather than panicking if the job's input files
+    /// aren't in place yet, so one bad `mrsubmit` call can't take down the RPC server's task
+    /// for every other job
+    fn submit_job(self, _: context::Context, job_id: i32, input_file_n: i32, reduce_n: i32, job_priority: u32, function_name: String, intermediate_format: IntermediateFormat) -> Self::SubmitJobFut {
+        match Job::new(job_id, input_file_n, reduce_n, job_priority, function_name.clone(), intermediate_format) {
+            Some(job) => {
+                println!("[Submit Job] New job #{} submitted with priority {} running \"{}\" ({} input files chunked into {} map tasks, {} reduce tasks)", job_id, job_priority, function_name, input_file_n, job.map_n, reduce_n);
+                self.jobs.lock().unwrap().push(job);
+                ready(true)
+            }
+            None => {
+                println!("[Submit Job] Failed to submit job #{}: one or more of its `pg-{}-*.txt` input files couldn't be opened", job_id, job_id);
+                ready(false)
+            }
+        }
+    }
+
+    /// The worker will call this once it holds a task, to learn which application and
+    /// intermediate format the task's job was registered with
+    fn get_job_config(self, _: context::Context, job_id: i32) -> Self::GetJobConfigFut {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.iter().find(|j| j.job_id == job_id).unwrap();
+        ready((job.function_name.clone(), job.intermediate_format))
+    }
+
     /// The worker will call this when finishing the map task
-    fn report_map_task_finish(self, _: context::Context, id: i32) -> Self::ReportMapTaskFinishFut {
-        // Serialize the current status before each report
-        println!("[Map] Begin serialize the Coordinator");
-        self.serialize();
-        let cur_map_tasks = self.map_tasks.lock().unwrap();
-        let mut cur_map_leases = self.map_leases.lock().unwrap();
+    fn report_map_task_finish(self, _: context::Context, job_id: i32, id: i32, worker_id: i32) -> Self::ReportMapTaskFinishFut {
+        // This worker no longer holds a task; it's about to ask for its next one
+        self.touch_worker(worker_id, WorkerState::Idle, -1);
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.iter_mut().find(|j| j.job_id == job_id).unwrap();
+
+        if job.map_done.contains(&id) {
+            // A backup worker raced on this task and lost; the winner already reported, so this
+            // is a harmless duplicate rather than a protocol violation
+            println!("[Map] Job #{} map task #{} was already reported finished by another worker, ignoring duplicate report", job_id, id);
+            return ready(true);
+        }
+        job.map_done.insert(id);
+
         // Sanity check
-        assert!(cur_map_tasks.contains_key(&id) && *cur_map_tasks.get(&id).unwrap() == true);
-        assert!(cur_map_leases.contains_key(&id));
-        println!("[Map] Map task #{} has been finished", id);
+        assert!(job.map_tasks.contains_key(&id) && *job.map_tasks.get(&id).unwrap() == true);
+        assert!(job.map_leases.contains_key(&id));
+        println!("[Map] Job #{} map task #{} has been finished", job_id, id);
+        // Record how long the task took, to keep the straggler-detection median up to date
+        if let Some(dispatch_time) = job.map_dispatch_time.get(&id) {
+            job.map_durations.push(dispatch_time.elapsed());
+        }
         // Remove the lease, since the task has been finished
-        cur_map_leases.remove_entry(&id);
-
-        // No need to do the following since the semantic of the map has changed
-        // Set the value to `true`, indicating the finish of the map task
-        // cur_map_tasks.insert(id, true);
+        job.map_leases.remove_entry(&id);
 
         // First let's check if there is staled map task
-        // FIXME: Same as `get_map_tasks`
-        for (&k, &v) in &cur_map_tasks.clone() {
+        // FIXME: Same as `dispatch_map_task`
+        for (&k, &v) in &job.map_tasks.clone() {
             if v {
                 continue;
             }
-            println!("[Map] Staled map task #{} detected when reporting, the previous worker may have gone offline, will assigned this task to a new worker", k);
+            println!("[Map] Job #{} staled map task #{} detected when reporting, the previous worker may have gone offline, will assigned this task to a new worker", job_id, k);
+            drop(jobs);
+            self.serialize();
             return ready(true);
         }
 
-        if !cur_map_leases.is_empty() {
-            println!("[Map] The map lease is not empty, there's still unfinished map tasks");
+        if !job.map_leases.is_empty() {
+            println!("[Map] Job #{} map lease is not empty, there's still unfinished map tasks", job_id);
+            drop(jobs);
+            self.serialize();
             return ready(true);
         }
 
-        let mut map_finish = self.map_finish.lock().unwrap();
-        let map_id = self.map_id.lock().unwrap();
-
-        if *map_id == self.map_n {
+        if job.map_id == job.map_n {
             // Otherwise, it's safe to set the `map_finish` to true
-            *map_finish = true;
-            println!("[Map] All map tasks have been finished by worker processes, the reduce phase will then begin!");
+            job.map_finish = true;
+            println!("[Map] All map tasks of job #{} have been finished by worker processes, its reduce phase will then begin!", job_id);
         }
 
+        drop(jobs);
+        println!("[Map] Begin serialize the Coordinator");
+        self.serialize();
         ready(true)
     }
 
     /// The worker will call this when finishing the reduce task
-    fn report_reduce_task_finish(self, _: context::Context, id: i32) -> Self::ReportReduceTaskFinishFut {
-        // Serialize the current status before each report
-        println!("[Reduce] Begin serialize the Coordinator");
-        self.serialize();
-        let cur_reduce_tasks = self.reduce_tasks.lock().unwrap();
-        let mut cur_reduce_leases = self.reduce_leases.lock().unwrap();
+    fn report_reduce_task_finish(self, _: context::Context, job_id: i32, id: i32, worker_id: i32) -> Self::ReportReduceTaskFinishFut {
+        // This worker no longer holds a task; it's about to ask for its next one
+        self.touch_worker(worker_id, WorkerState::Idle, -1);
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.iter_mut().find(|j| j.job_id == job_id).unwrap();
+
+        if job.reduce_done.contains(&id) {
+            // A backup worker raced on this task and lost; the winner already reported, so this
+            // is a harmless duplicate rather than a protocol violation
+            println!("[Reduce] Job #{} reduce task #{} was already reported finished by another worker, ignoring duplicate report", job_id, id);
+            return ready(true);
+        }
+        job.reduce_done.insert(id);
+
         // Sanity check
-        assert!(cur_reduce_tasks.contains_key(&id) && *cur_reduce_tasks.get(&id).unwrap() == true);
-        assert!(cur_reduce_leases.contains_key(&id));
-        println!("[Reduce] Reduce task #{} has been finished", id);
+        assert!(job.reduce_tasks.contains_key(&id) && *job.reduce_tasks.get(&id).unwrap() == true);
+        assert!(job.reduce_leases.contains_key(&id));
+        println!("[Reduce] Job #{} reduce task #{} has been finished", job_id, id);
+        // Record how long the task took, to keep the straggler-detection median up to date
+        if let Some(dispatch_time) = job.reduce_dispatch_time.get(&id) {
+            job.reduce_durations.push(dispatch_time.elapsed());
+        }
         // Remove the lease, since the task has been finished
-        cur_reduce_leases.remove_entry(&id);
-
-        // No need to do the following since the semantic of the map has changed
-        // Set the value to `true`, indicating the finish of the reduce task
-        // cur_reduce_tasks.insert(id, true);
+        job.reduce_leases.remove_entry(&id);
 
         // First let's check if there is staled reduce task
-        // FIXME: Same as `get_map_tasks`
-        for (&k, &v) in &cur_reduce_tasks.clone() {
+        // FIXME: Same as `dispatch_reduce_task`
+        for (&k, &v) in &job.reduce_tasks.clone() {
             if v {
                 continue;
             }
-            println!("[Reduce] Staled reduce task #{} detected when reporting, the previous worker may have gone offline, will assigned this task to a new worker", k);
+            println!("[Reduce] Job #{} staled reduce task #{} detected when reporting, the previous worker may have gone offline, will assigned this task to a new worker", job_id, k);
+            drop(jobs);
+            self.serialize();
             return ready(true);
         }
 
-        if !cur_reduce_leases.is_empty() {
-            println!("[Reduce] The reduce lease is not empty, there's still unfinished reduce tasks");
+        if !job.reduce_leases.is_empty() {
+            println!("[Reduce] Job #{} reduce lease is not empty, there's still unfinished reduce tasks", job_id);
+            drop(jobs);
+            self.serialize();
             return ready(true);
         }
 
-        let mut reduce_finish = self.reduce_finish.lock().unwrap();
-        let reduce_id = self.reduce_id.lock().unwrap();
-        
-        if *reduce_id == self.reduce_n {
+        if job.reduce_id == job.reduce_n {
             // Otherwise, it's safe to set the `reduce_finish` to true
-            *reduce_finish = true;
-            println!("[Reduce] All reduce tasks have been finished by worker processes, MapReduce has finished!");
+            job.reduce_finish = true;
+            println!("[Reduce] All reduce tasks of job #{} have been finished by worker processes, this job has finished!", job_id);
         }
 
+        drop(jobs);
+        println!("[Reduce] Begin serialize the Coordinator");
+        self.serialize();
         ready(true)
     }
-}
\ No newline at end of file
+}
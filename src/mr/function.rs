@@ -1,23 +1,92 @@
-//! The hard-coded map reduce functions, may be changed to dynamic linking shared library in the future
+//! The pluggable map/reduce application registry, resolved by name at runtime so the crate
+//! isn't hard-wired to a single application. A name matching one of the built-in applications
+//! below resolves to that statically-linked implementation; any other name is treated as a path
+//! to a `cdylib` plugin and loaded dynamically via `mr::plugin`
+
+use crate::mr::worker::KeyValue;
+
+/// Every pluggable MapReduce application, whether built in or dynamically loaded, implements
+/// this
+pub trait MrApp: Send {
+    fn map(&self, input: &str) -> Vec<KeyValue>;
+    fn reduce(&self, key: &str, values: Vec<&str>) -> String;
+    /// Pre-aggregate one key's values from a single map task's own output, before they're ever
+    /// written to an intermediate file, so less data needs to be shuffled to reduce workers (for
+    /// word count this sums the local `1`s). Returns `None` to opt out, in which case the key's
+    /// values are carried through unmerged exactly as `map` produced them
+    fn combine(&self, _key: &str, _values: Vec<&str>) -> Option<String> {
+        None
+    }
+}
 
 /// Word Count application
 pub mod wc {
     use regex::Regex;
 
-    use crate::mr::worker::KeyValue;
+    use crate::mr::{function::MrApp, worker::KeyValue};
+
+    pub struct WordCount;
+
+    impl MrApp for WordCount {
+        fn map(&self, input: &str) -> Vec<KeyValue> {
+            let re = Regex::new(r"[^\w\s]").unwrap();
+            let result = re.replace_all(input, "");
+            result
+                .split_whitespace()
+                .map(|x| KeyValue::new(x.to_owned(), 1.to_string()))
+                .collect()
+        }
+
+        fn reduce(&self, _key: &str, values: Vec<&str>) -> String {
+            // Sums the values as integers rather than just counting them, so this also works
+            // when `combine` has already folded several `1`s into one partial count
+            values.iter().map(|v| v.parse::<u64>().unwrap()).sum::<u64>().to_string()
+        }
+
+        fn combine(&self, _key: &str, values: Vec<&str>) -> Option<String> {
+            Some(values.iter().map(|v| v.parse::<u64>().unwrap()).sum::<u64>().to_string())
+        }
+    }
+}
+
+/// Grep application: emits every line containing `PATTERN`
+pub mod grep {
+    use crate::mr::{function::MrApp, worker::KeyValue};
+
+    // TODO: make the search pattern a per-job parameter instead of compiled in, once jobs can
+    // carry arbitrary application config rather than just a function name
+    const PATTERN: &str = "error";
 
-    pub fn map(input: &str) -> Vec<KeyValue> {
-        let re = Regex::new(r"[^\w\s]").unwrap();
-        let result = re.replace_all(input, "");
-        result
-            .split_whitespace()
-            .map(|x| KeyValue::new(x.to_owned(), 1.to_string()))
-            .collect()
+    pub struct Grep;
+
+    impl MrApp for Grep {
+        fn map(&self, input: &str) -> Vec<KeyValue> {
+            input
+                .lines()
+                .filter(|line| line.contains(PATTERN))
+                .map(|line| KeyValue::new(PATTERN.to_string(), line.to_string()))
+                .collect()
+        }
+
+        fn reduce(&self, _key: &str, values: Vec<&str>) -> String {
+            values.join("\n")
+        }
+
+        // No `combine` override: matching lines shouldn't be merged before the shuffle, only
+        // concatenated once during the real reduce
     }
+}
 
-    pub fn reduce(_key: &str, value: Vec<&str>) -> String {
-        value.len().to_string()
+/// Resolve a job's function name (as given to `submit_job`) to its registered application. A
+/// name matching one of the built-in applications is resolved statically; any other name is
+/// treated as a path to a `cdylib` plugin and loaded dynamically, so a worker can run an
+/// application that was never compiled into this crate
+pub fn resolve(name: &str) -> Box<dyn MrApp> {
+    match name {
+        "wc" => Box::new(wc::WordCount),
+        "grep" => Box::new(grep::Grep),
+        path => Box::new(crate::mr::plugin::Plugin::load(path)),
     }
 }
 
-// TODO: Add more functions for MapReduce applications here
\ No newline at end of file
+// TODO: Add more functions for MapReduce applications here
@@ -0,0 +1,63 @@
+//! Dynamically loadable `MrApp` plugins, loaded from a user-supplied `cdylib` at its well-known
+//! C-ABI entry point, so a worker can run applications that were never compiled into this crate
+//! (the built-in `wc`/`grep` applications in `mr::function` remain statically linked)
+
+use std::ffi::OsStr;
+
+use libloading::{Library, Symbol};
+
+use crate::mr::function::MrApp;
+
+/// The well-known symbol every plugin `cdylib` must export, e.g.:
+/// ```ignore
+/// #[no_mangle]
+/// pub extern "C" fn mr_app_create() -> *mut dyn MrApp {
+///     Box::into_raw(Box::new(MyApp))
+/// }
+/// ```
+const ENTRY_SYMBOL: &[u8] = b"mr_app_create";
+
+/// The C-ABI entry point signature every plugin `cdylib` exports under `ENTRY_SYMBOL`, handing
+/// ownership of a freshly heap-allocated app instance to the loader
+type MrAppEntry = unsafe extern "C" fn() -> *mut dyn MrApp;
+
+/// A dynamically loaded `MrApp`. Bundles the loaded library together with the app it produced,
+/// since the app's vtable lives inside the library's mapped memory and must not be unmapped
+/// (i.e. the library dropped) while the app is still in use
+pub struct Plugin {
+    /// Declared before `_library` so it is dropped first: fields drop in declaration order
+    app: Box<dyn MrApp>,
+    /// Kept alive for as long as `app` may be called; never accessed directly after loading
+    _library: Library,
+}
+
+impl Plugin {
+    /// Load a plugin `cdylib` from `path` and call its `mr_app_create` entry point
+    pub fn load<P: AsRef<OsStr>>(path: P) -> Plugin {
+        let library = unsafe {
+            Library::new(path).expect("[Plugin] Failed to load the plugin cdylib")
+        };
+        // Safety: the loaded library is kept alive for as long as `app` is, inside this `Plugin`
+        let app = unsafe {
+            let entry: Symbol<MrAppEntry> = library
+                .get(ENTRY_SYMBOL)
+                .expect("[Plugin] Plugin cdylib is missing the `mr_app_create` entry point");
+            Box::from_raw(entry())
+        };
+        Plugin { _library: library, app }
+    }
+}
+
+impl MrApp for Plugin {
+    fn map(&self, input: &str) -> Vec<crate::mr::worker::KeyValue> {
+        self.app.map(input)
+    }
+
+    fn reduce(&self, key: &str, values: Vec<&str>) -> String {
+        self.app.reduce(key, values)
+    }
+
+    fn combine(&self, key: &str, values: Vec<&str>) -> Option<String> {
+        self.app.combine(key, values)
+    }
+}
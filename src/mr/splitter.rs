@@ -0,0 +1,99 @@
+//! Content-defined chunking for input splitting, so map fan-out isn't bounded by file count
+
+use std::{fs::File, io::Read};
+
+/// The rolling hash window size, in bytes
+const WINDOW_SIZE: usize = 48;
+/// A chunk boundary is declared whenever `hash & CHUNK_MASK == CHUNK_MASK`, chosen so the
+/// average chunk size is ~1 MiB
+const CHUNK_MASK: u64 = (1 << 20) - 1;
+/// No chunk may be smaller than this, so boundaries can't cluster into a run of tiny chunks
+const MIN_CHUNK_SIZE: u64 = 256 * 1024;
+/// No chunk may be larger than this, so an unlucky run of hashes can't grow a chunk unboundedly
+const MAX_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+/// The rolling polynomial hash base
+const RABIN_BASE: u64 = 1_000_000_007;
+
+/// A single map task's byte range within an input file: `[offset, offset + length)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub file: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Slide a fixed-size window over `file_name`'s contents, computing a rolling polynomial hash,
+/// and declare a chunk boundary whenever the hash satisfies `CHUNK_MASK` (clamped to
+/// `[MIN_CHUNK_SIZE, MAX_CHUNK_SIZE]`). Boundaries are then snapped forward to the next newline
+/// so a whitespace-delimited key (e.g. a word-count word) is never split across two chunks.
+/// Returns `None` if `file_name` can't be opened or read, rather than panicking, so a caller
+/// building a job out of several input files can fail that one job instead of taking down
+/// whatever task called in (e.g. the RPC server's own task)
+pub fn split_file(file_name: &str) -> Option<Vec<Chunk>> {
+    let mut file = File::open(file_name).ok()?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents).ok()?;
+
+    if contents.is_empty() {
+        return Some(Vec::new());
+    }
+
+    // Precompute `RABIN_BASE ^ WINDOW_SIZE`, used to slide the oldest byte out of the window
+    let base_pow = RABIN_BASE.wrapping_pow(WINDOW_SIZE as u32);
+
+    let mut boundaries = Vec::new();
+    let mut chunk_start = 0usize;
+    let mut hash = 0u64;
+
+    for i in 0..contents.len() {
+        hash = hash.wrapping_mul(RABIN_BASE).wrapping_add(contents[i] as u64);
+        if i >= WINDOW_SIZE {
+            let dropped = contents[i - WINDOW_SIZE] as u64;
+            hash = hash.wrapping_sub(dropped.wrapping_mul(base_pow));
+        }
+
+        if i + 1 < chunk_start {
+            // The previous boundary was snapped forward past `i + 1` (looking for the next
+            // newline); these bytes already belong to the chunk that boundary just closed, so
+            // there's no new chunk to measure yet. Still fall through to update the rolling
+            // hash above on every byte, only skipping the boundary check itself
+            continue;
+        }
+
+        let chunk_len = (i + 1 - chunk_start) as u64;
+        if i + 1 < WINDOW_SIZE || chunk_len < MIN_CHUNK_SIZE {
+            // The window hasn't filled yet, or the chunk is too small to split
+            continue;
+        }
+        if chunk_len < MAX_CHUNK_SIZE && hash & CHUNK_MASK != CHUNK_MASK {
+            continue;
+        }
+
+        // Snap the boundary forward to the next newline so no key spans two chunks
+        let mut boundary = i + 1;
+        while boundary < contents.len() && contents[boundary - 1] != b'\n' {
+            boundary += 1;
+        }
+        boundaries.push(boundary);
+        chunk_start = boundary;
+        if chunk_start >= contents.len() {
+            // The forward newline scan reached the end of the file (e.g. the file's last line
+            // has no trailing newline); there's no more data left to split
+            break;
+        }
+    }
+    if chunk_start < contents.len() {
+        boundaries.push(contents.len());
+    }
+
+    let mut chunks = Vec::new();
+    let mut start = 0u64;
+    for boundary in boundaries {
+        let end = boundary as u64;
+        if end > start {
+            chunks.push(Chunk { file: file_name.to_string(), offset: start, length: end - start });
+        }
+        start = end;
+    }
+    Some(chunks)
+}
@@ -1,8 +1,81 @@
-use std::{fs::File, io::Read, collections::hash_map::DefaultHasher, hash::{Hash, Hasher}};
+use std::{fs::File, io::{Read, Seek, SeekFrom}, collections::{VecDeque, hash_map::DefaultHasher}, hash::{Hash, Hasher}, time::Duration};
 
 use tokio::io::AsyncWriteExt;
 
-use crate::mr::function::wc;
+use crate::mr::function;
+
+/// Adaptive idle backoff, porting Garage's "tranquilizer" idea: sleep for a tunable multiple of
+/// the rolling average duration of recently completed tasks rather than a fixed interval, so a
+/// worker's idle polling self-tunes to the job's own granularity instead of guessing at one
+pub struct Tranquilizer {
+    /// Sliding window of recently completed task durations, bounded by `window_size`
+    durations: VecDeque<Duration>,
+    /// Max number of durations to keep in the window
+    window_size: usize,
+    /// Sleep for this many multiples of the rolling average task duration when idle
+    multiplier: u32,
+    /// Never sleep longer than this, regardless of the rolling average
+    max_backoff: Duration,
+    /// Total tasks completed so far, reported alongside throughput in log output
+    completed_n: u64,
+}
+
+impl Tranquilizer {
+    pub fn new(window_size: usize, multiplier: u32, max_backoff: Duration) -> Self {
+        Self {
+            durations: VecDeque::with_capacity(window_size),
+            window_size,
+            multiplier,
+            max_backoff,
+            completed_n: 0,
+        }
+    }
+
+    /// Record a just-completed task's wall-clock duration
+    pub fn record(&mut self, duration: Duration) {
+        if self.durations.len() == self.window_size {
+            self.durations.pop_front();
+        }
+        self.durations.push_back(duration);
+        self.completed_n += 1;
+    }
+
+    /// The rolling average task duration over the current window, `None` until at least one
+    /// task has completed
+    fn average_duration(&self) -> Option<Duration> {
+        if self.durations.is_empty() {
+            return None;
+        }
+        let total: Duration = self.durations.iter().sum();
+        Some(total / self.durations.len() as u32)
+    }
+
+    /// How long to sleep when the coordinator reports no available work: a tunable multiple of
+    /// the rolling average task duration, capped at `max_backoff`. Falls back to `max_backoff`
+    /// itself until the window has learned the job's granularity from at least one task
+    pub fn backoff(&self) -> Duration {
+        match self.average_duration() {
+            Some(avg) => std::cmp::min(avg * self.multiplier, self.max_backoff),
+            None => self.max_backoff,
+        }
+    }
+
+    /// Rolling throughput over the current window, as `(tasks/sec, avg task ms)`, for log output
+    pub fn throughput(&self) -> (f64, f64) {
+        match self.average_duration() {
+            Some(avg) => {
+                let avg_ms = avg.as_secs_f64() * 1000.0;
+                let tasks_per_sec = if avg_ms > 0.0 { 1000.0 / avg_ms } else { 0.0 };
+                (tasks_per_sec, avg_ms)
+            }
+            None => (0.0, 0.0),
+        }
+    }
+
+    pub fn completed_n(&self) -> u64 {
+        self.completed_n
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct KeyValue {
@@ -16,13 +89,157 @@ impl KeyValue {
     }
 }
 
+/// The on-disk format used for a job's intermediate (map output) files. The original
+/// space-separated plaintext format is kept as the default for backwards compatibility, but it
+/// breaks as soon as a key or value contains a space or newline, so jobs whose map output may
+/// contain arbitrary text can opt into one of the other two instead
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum IntermediateFormat {
+    /// `key value\n`, one record per line
+    PlainText,
+    /// Big-endian `u32` key length, key bytes, `u32` value length, value bytes, back to back;
+    /// survives any key/value content since no delimiter needs to be searched for
+    LengthPrefixed,
+    /// One `{"key":"...","value":"..."}` object per line, escaping quotes, backslashes, newlines,
+    /// carriage returns and tabs the way JSON strings do (other control characters and `\u`
+    /// escapes are not handled); survives embedded spaces/newlines without needing a real JSON parser
+    JsonLine,
+}
+
+/// Escape a string for embedding inside a `JsonLine` record
+fn json_escape(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Reverse of `json_escape`; `s` must include the surrounding quotes
+fn json_unescape(s: &str) -> String {
+    let inner = &s[1..s.len() - 1];
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('"') => out.push('"'),
+            Some('\\') => out.push('\\'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+/// Encode a single key-value record in the given intermediate format
+fn encode_record(format: IntermediateFormat, key: &str, value: &str) -> Vec<u8> {
+    match format {
+        IntermediateFormat::PlainText => format!("{} {}\n", key, value).into_bytes(),
+        IntermediateFormat::LengthPrefixed => {
+            let mut buf = Vec::new();
+            buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            buf.extend_from_slice(key.as_bytes());
+            buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            buf.extend_from_slice(value.as_bytes());
+            buf
+        }
+        IntermediateFormat::JsonLine => format!("{{\"key\":{},\"value\":{}}}\n", json_escape(key), json_escape(value)).into_bytes(),
+    }
+}
+
+/// Decode every key-value record out of a full intermediate file's bytes
+fn decode_records(format: IntermediateFormat, bytes: &[u8]) -> Vec<KeyValue> {
+    match format {
+        IntermediateFormat::PlainText => {
+            std::str::from_utf8(bytes).unwrap()
+                .split("\n")
+                .filter(|x| !x.is_empty())
+                .map(|x| {
+                    let line = x.split(" ").collect::<Vec<&str>>();
+                    assert!(line.len() == 2);
+                    KeyValue::new(line[0].to_owned(), line[1].to_owned())
+                }).collect()
+        }
+        IntermediateFormat::LengthPrefixed => {
+            let mut records = Vec::new();
+            let mut cursor = 0usize;
+            while cursor < bytes.len() {
+                let key_len = u32::from_be_bytes(bytes[cursor..cursor + 4].try_into().unwrap()) as usize;
+                cursor += 4;
+                let key = std::str::from_utf8(&bytes[cursor..cursor + key_len]).unwrap().to_owned();
+                cursor += key_len;
+                let value_len = u32::from_be_bytes(bytes[cursor..cursor + 4].try_into().unwrap()) as usize;
+                cursor += 4;
+                let value = std::str::from_utf8(&bytes[cursor..cursor + value_len]).unwrap().to_owned();
+                cursor += value_len;
+                records.push(KeyValue::new(key, value));
+            }
+            records
+        }
+        IntermediateFormat::JsonLine => {
+            std::str::from_utf8(bytes).unwrap()
+                .split("\n")
+                .filter(|x| !x.is_empty())
+                .map(|line| {
+                    // `{"key":"...","value":"..."}` - split on the fixed field boundaries rather
+                    // than pulling in a full JSON parser for two known fields
+                    let rest = line.strip_prefix("{\"key\":").unwrap();
+                    let (key_part, rest) = split_json_string(rest);
+                    let rest = rest.strip_prefix(",\"value\":").unwrap();
+                    let (value_part, _) = split_json_string(rest);
+                    KeyValue::new(json_unescape(key_part), json_unescape(value_part))
+                }).collect()
+        }
+    }
+}
+
+/// Split a leading JSON string literal (including its quotes) off the front of `s`, returning
+/// `(literal, rest)`; used to pick apart the minimal `JsonLine` record format above
+fn split_json_string(s: &str) -> (&str, &str) {
+    let bytes = s.as_bytes();
+    assert_eq!(bytes[0], b'"');
+    let mut i = 1;
+    while bytes[i] != b'"' {
+        if bytes[i] == b'\\' {
+            // Skip the escaped character, so `\"` and `\\` never end the literal early or late
+            i += 1;
+        }
+        i += 1;
+    }
+    s.split_at(i + 1)
+}
+
 /// One worker will only be touched by one worker process, there is no need to synchronize anything
 /// We are thus lock-free!
 pub struct Worker {
     /// The current state, `false` represents `map phase`, `true` represents `reduce phase`
     state: bool,
+    /// The id of the job the currently held task belongs to, will be -1 if the worker holds no task
+    job_id: i32,
+    /// The name of the registered application (see `mr::function::resolve`) the current job runs
+    function_name: String,
+    /// The on-disk format of the current job's intermediate files
+    intermediate_format: IntermediateFormat,
     /// The map task id, indicating which input files to read & map, will be -1 if the current job finished
     map_task_id: i32,
+    /// The content-defined chunk (file, byte offset, length) the current map task reads from,
+    /// fetched from the coordinator once `map_task_id` is known
+    map_chunk: (String, u64, u64),
     /// The reduce task id, indicating which intermediate files to read & reduce, will be -1 if the current job finished
     reduce_task_id: i32,
     /// The total map tasks, used to read intermediate files
@@ -31,25 +248,16 @@ pub struct Worker {
     reduce_n: i32,
 }
 
-/// Calls the user-defined map function
-pub fn call_map_func(map_func: Box<dyn Fn(&str) -> Vec<KeyValue> + Send>, contents: &str) -> Vec<KeyValue> {
-    map_func(contents)
-}
-
-/// Calls the user-defined reduce function
-pub fn call_reduce_func(
-        reduce_func: Box<dyn Fn(&str, Vec<&str>) -> String + Send>,
-        key: &str,
-        value: Vec<&str>) -> String {
-    reduce_func(key, value)
-}
-
 impl Worker {
     pub fn new(map_n: i32, reduce_n: i32) -> Self {
         Self {
             // The initial state should be false
             state: false,
+            job_id: -1,
+            function_name: String::from("wc"),
+            intermediate_format: IntermediateFormat::PlainText,
             map_task_id: -1,
+            map_chunk: (String::new(), 0, 0),
             reduce_task_id: -1,
             map_n,
             reduce_n,
@@ -67,6 +275,21 @@ impl Worker {
         self.state = true;
     }
 
+    pub fn get_job_id(&self) -> i32 {
+        self.job_id
+    }
+
+    pub fn set_job_id(&mut self, job_id: i32) {
+        self.job_id = job_id;
+    }
+
+    /// Record which application and intermediate format the currently-held task's job uses,
+    /// as resolved by the coordinator
+    pub fn set_job_config(&mut self, function_name: String, intermediate_format: IntermediateFormat) {
+        self.function_name = function_name;
+        self.intermediate_format = intermediate_format;
+    }
+
     pub fn get_map_id(&self) -> i32 {
         self.map_task_id
     }
@@ -75,6 +298,12 @@ impl Worker {
         self.map_task_id = map_task_id;
     }
 
+    /// Record which `(file, offset, length)` chunk the current map task should read, as
+    /// resolved by the coordinator's content-defined splitter
+    pub fn set_map_chunk(&mut self, file: String, offset: u64, length: u64) {
+        self.map_chunk = (file, offset, length);
+    }
+
     pub fn get_reduce_id(&self) -> i32 {
         self.reduce_task_id
     }
@@ -84,45 +313,41 @@ impl Worker {
     }
 
     fn read_file_to_mem_map(&self) -> String {
-        let file_name = "pg-".to_string() + &self.map_task_id.to_string() + ".txt";
+        // A map task now reads a content-defined byte range of an input file rather than the
+        // whole file, so map fan-out isn't bounded by the number of physical input files
+        let (file_name, offset, length) = &self.map_chunk;
         println!(
-            "[Map] Worker is reading input file {} for map task #{}",
+            "[Map] Worker is reading {} bytes at offset {} of input file {} for map task #{}",
+            length,
+            offset,
             file_name,
             self.map_task_id
         );
         let mut file = File::open(file_name).unwrap();
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
-        contents
+        file.seek(SeekFrom::Start(*offset)).unwrap();
+        let mut buf = vec![0u8; *length as usize];
+        file.read_exact(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
     }
 
     fn read_file_to_mem_reduce(&self) -> Vec<KeyValue> {
-        // The intermediate files to read is from `mr-0-{reduce_task_id}.txt` to `mr-{map_n - 1}-{reduce_task_id}.txt`
-        // The output files should be `mr-{reduce_task_id}.txt`
+        // The intermediate files to read is from `mr-{job}-0-{reduce_task_id}.txt` to
+        // `mr-{job}-{map_n - 1}-{reduce_task_id}.txt`
+        // The output files should be `mr-{job}-{reduce_task_id}.txt`
         let mut key_value_vec = Vec::new();
 
         for i in 0..self.map_n {
-            let file_name = "mr-".to_string() + &i.to_string() + "-" + &self.reduce_task_id.to_string() + ".txt";
+            let file_name = "mr-".to_string() + &self.job_id.to_string() + "-" + &i.to_string() + "-" + &self.reduce_task_id.to_string() + ".txt";
             println!(
                 "[Reduce] Worker is reading intermediate file {} for reduce task #{}",
                 file_name,
                 self.reduce_task_id
             );
             let mut file = File::open(file_name).unwrap();
-            let mut contents = String::new();
-            file.read_to_string(&mut contents).unwrap();
-            // Process the contents line by line
-            let mut key_value_pairs = contents
-                .split("\n")
-                .filter(|x| !x.is_empty())
-                .map(|x| {
-                    let line = x.split(" ").collect::<Vec<&str>>();
-                    assert!(line.len() == 2);
-                    let (key, value) = (line[0], line[1]);
-                    KeyValue::new(key.to_owned(), value.to_owned())
-                }).collect::<Vec<KeyValue>>();
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).unwrap();
             // Append the newly generated key-value pairs to the result vector
-            key_value_vec.append(&mut key_value_pairs);
+            key_value_vec.append(&mut decode_records(self.intermediate_format, &contents));
         }
 
         key_value_vec
@@ -135,13 +360,19 @@ impl Worker {
     }
 
     async fn write_key_value_to_file(&self, key_value_pairs: Vec<KeyValue>) -> anyhow::Result<bool> {
-        let mut file_vec = Vec::new();
+        // Since a straggler task may be raced by a backup worker on the same task id, both
+        // workers would otherwise write to the exact same `mr-{job}-{map}-{reduce}.txt` files; write
+        // to a process-unique temp path instead and atomically rename on success, so a losing
+        // racer can at worst clobber the final file with a complete one, never a partial one
+        let temp_suffix = format!(".tmp.{}", std::process::id());
+        let mut buf_vec: Vec<Vec<u8>> = vec![Vec::new(); self.reduce_n as usize];
         let mut file_name_vec = Vec::new();
+        let mut temp_name_vec = Vec::new();
         for i in 0..self.reduce_n {
-            let file_name= "mr-".to_string() + &self.map_task_id.to_string() + "-" + &i.to_string() + ".txt";
-            let file = tokio::fs::File::create(file_name.clone()).await?;
-            file_vec.push(file);
+            let file_name = "mr-".to_string() + &self.job_id.to_string() + "-" + &self.map_task_id.to_string() + "-" + &i.to_string() + ".txt";
+            let temp_name = file_name.clone() + &temp_suffix;
             file_name_vec.push(file_name);
+            temp_name_vec.push(temp_name);
         }
 
         for kv in key_value_pairs {
@@ -149,12 +380,21 @@ impl Worker {
             let index = ((Self::cal_hash_for_key(&key)) % self.reduce_n as u64) as i32;
             // Sanity check
             assert!(index >= 0 && index < self.reduce_n);
-            // Append the current key-value pair to the intermediate file asynchronously
-            file_vec[index as usize].write_all(format!("{} {}\n", key, value).as_bytes()).await?;
+            // Append the current key-value pair to the in-memory buffer for this partition
+            buf_vec[index as usize].extend(encode_record(self.intermediate_format, &key, &value));
+        }
+
+        // Each temp file is published (renamed into place) only once it's fully written, so a
+        // losing backup racer can never observe (or produce) a half-written intermediate file
+        for i in 0..self.reduce_n as usize {
+            let mut file = tokio::fs::File::create(&temp_name_vec[i]).await?;
+            file.write_all(&buf_vec[i]).await?;
+            file.flush().await?;
+            tokio::fs::rename(&temp_name_vec[i], &file_name_vec[i]).await?;
             println!(
                 "[Map] Worker finish mapping task #{}, the intermediate result has been written to {}",
                 self.map_task_id,
-                file_name_vec[index as usize]
+                file_name_vec[i]
             );
         }
 
@@ -169,13 +409,30 @@ impl Worker {
         assert!(self.map_task_id != -1);
         // Let's read the file into memory
         let contents = self.read_file_to_mem_map();
-        // Then get the key-value pairs that we'd like to map to intermediate files
-        let key_value_pairs = call_map_func(
-            Box::new(wc::map),
-            &contents
-        );
+        // Resolve the job's registered application and get the key-value pairs to map
+        let app = function::resolve(&self.function_name);
+        let mut key_value_pairs = app.map(&contents);
+        // Pre-aggregate this task's own output per key through the application's optional
+        // combiner before it's ever written to an intermediate file, cutting the volume that
+        // needs to be shuffled to reduce workers; falls back to no combining when the
+        // application's `combine` returns `None`
+        key_value_pairs.sort_by(|lhs, rhs| lhs.key.cmp(&rhs.key));
+        let mut combined = Vec::new();
+        let mut i = 0;
+        while i < key_value_pairs.len() {
+            let mut j = i;
+            while j < key_value_pairs.len() && key_value_pairs[j].key == key_value_pairs[i].key {
+                j += 1;
+            }
+            let values = key_value_pairs[i..j].iter().map(|kv| kv.value.as_str()).collect::<Vec<&str>>();
+            match app.combine(&key_value_pairs[i].key, values) {
+                Some(combined_value) => combined.push(KeyValue::new(key_value_pairs[i].key.clone(), combined_value)),
+                None => combined.extend(key_value_pairs[i..j].iter().cloned()),
+            }
+            i = j;
+        }
         // Write the key-value pairs to the intermediate files according to the index (hash(key) % reduce_n)
-        assert!(self.write_key_value_to_file(key_value_pairs).await?);
+        assert!(self.write_key_value_to_file(combined).await?);
         // Finish the current map task, set the task id back to -1
         self.set_map_id(-1);
         Ok(true)
@@ -193,22 +450,24 @@ impl Worker {
         key_value_contents.sort_by(|lhs, rhs| {
             lhs.key.cmp(&rhs.key)
         });
+        // Resolve the job's registered application
+        let app = function::resolve(&self.function_name);
         // Traverse the key-value pairs for the actual reduce phase
         let mut kv_vec = Vec::new();
         let mut prev = String::new();
-        let file_name = "mr-".to_string() + &self.reduce_task_id.to_string() + ".txt";
-        let mut file = tokio::fs::File::create(file_name.clone()).await?;
+        let file_name = "mr-".to_string() + &self.job_id.to_string() + "-" + &self.reduce_task_id.to_string() + ".txt";
+        // Same rationale as `write_key_value_to_file`: write to a process-unique temp path and
+        // atomically rename on success, so a coordinator shutdown mid-write (or a losing backup
+        // racer) can never leave a half-written final output file behind
+        let temp_name = format!("{}.tmp.{}", file_name, std::process::id());
+        let mut file = tokio::fs::File::create(&temp_name).await?;
         for kv in &key_value_contents {
             if prev.is_empty() {
                 prev = kv.key.clone();
             }
             if kv.key != prev {
                 // Let's reduce!
-                let reduce_result = call_reduce_func(
-                    Box::new(wc::reduce),
-                    &prev,
-                    kv_vec.clone()
-                );
+                let reduce_result = app.reduce(&prev, kv_vec.clone());
                 // The end of the collection for one key, need to write the result to output file
                 file.write_all(format!("{} {}\n", prev, reduce_result).as_bytes()).await?;
                 // Clear the kv vector
@@ -218,6 +477,8 @@ impl Worker {
             }
             kv_vec.push(&kv.value);
         }
+        file.flush().await?;
+        tokio::fs::rename(&temp_name, &file_name).await?;
         println!(
             "[Reduce] Worker finish reducing task #{}, the final output has been written to {}",
             self.reduce_task_id,
@@ -227,4 +488,4 @@ impl Worker {
         self.set_reduce_id(-1);
         Ok(true)
     }
-}
\ No newline at end of file
+}